@@ -0,0 +1,219 @@
+use super::*;
+
+use crate::timer;
+
+const RESET_SUPPORTED: u32 = 1 << 10;
+const HARDWARE_REDUCED: u32 = 1 << 20;
+
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+// bits of the pm1 control registers
+const SCI_ENABLED: u64 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u64 = 10;
+const SLEEP_ENABLE: u64 = 1 << 13;
+
+// the aml opcodes around the package that holds the sleep type values of a sleep state
+const NAME_OP: u8 = 0x08;
+const ROOT_PREFIX: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0a;
+
+// the command the 8042 pulses the reset line of the cpu for
+const KEYBOARD_COMMAND: u16 = 0x64;
+const KEYBOARD_RESET: u8 = 0xfe;
+
+// how long the firmware and the chipset get before we give up on them
+const ENABLE_TIMEOUT_MS: u64 = 3000;
+const RESET_DELAY_MS: u64 = 100;
+
+
+// the offsets of the fields we care about, counted from the end of the header
+#[non_exhaustive]
+struct Field;
+
+impl Field {
+    const DSDT: usize = 4;
+    const SCI_INTERRUPT: usize = 10;
+    const SMI_COMMAND: usize = 12;
+    const ACPI_ENABLE: usize = 16;
+    const PM1A_CONTROL: usize = 28;
+    const PM1B_CONTROL: usize = 32;
+    const PM1_CONTROL_LENGTH: usize = 53;
+    const BOOT_ARCH: usize = 73;
+    const FLAGS: usize = 76;
+    // everything from here on only exists since acpi 2.0
+    const RESET_REGISTER: usize = 80;
+    const RESET_VALUE: usize = 92;
+    const X_DSDT: usize = 104;
+    const X_PM1A_CONTROL: usize = 136;
+    const X_PM1B_CONTROL: usize = 148;
+}
+
+// the fixed hardware of acpi, mostly the power management registers. the b blocks only exist on
+// machines that split them between two chips.
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    // writing acpi_enable here hands the power management registers from the firmware to us
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    pub boot_arch: u16,
+    pub flags: u32,
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    pub fn has_8042(&self) -> bool {
+        self.boot_arch & BOOT_ARCH_8042 != 0
+    }
+
+    // there is no fixed hardware at all on these, everything goes through the aml
+    pub fn hardware_reduced(&self) -> bool {
+        self.flags & HARDWARE_REDUCED != 0
+    }
+
+    // writes the reset value to the reset register, and has the 8042 pull the reset line if that
+    // did not do it. returns if neither managed to reset the machine.
+    pub fn reset(&self) -> Result<(), AcpiError> {
+        if let Some((register, value)) = self.reset {
+            unsafe { register.store(value as u64)?; }
+
+            timer::sleep_ms(RESET_DELAY_MS);
+        }
+
+        if self.has_8042() {
+            unsafe { Port::<u8>::new(KEYBOARD_COMMAND).write(KEYBOARD_RESET); }
+
+            timer::sleep_ms(RESET_DELAY_MS);
+        }
+
+        Err(AcpiError::Unsupported)
+    }
+
+    // puts the machine into s5, returns if it is still running afterwards
+    pub fn shutdown(&self) -> Result<(), AcpiError> {
+        if self.hardware_reduced() {
+            return Err(AcpiError::Unsupported);
+        }
+
+        let control = self.pm1a_control.ok_or(AcpiError::NotFound)?;
+        let (sleep_type_a, sleep_type_b) = sleep_types(b"_S5_")?;
+
+        unsafe {
+            self.enable(&control)?;
+
+            control.store(sleep_type_a << SLEEP_TYPE_SHIFT | SLEEP_ENABLE)?;
+
+            if let Some(control) = self.pm1b_control {
+                control.store(sleep_type_b << SLEEP_TYPE_SHIFT | SLEEP_ENABLE)?;
+            }
+        }
+
+        timer::sleep_ms(RESET_DELAY_MS);
+
+        Err(AcpiError::Unsupported)
+    }
+
+    // hands the power management registers from the firmware to us. most firmware boots with acpi
+    // enabled already, there is no smi command port then.
+    unsafe fn enable(&self, control: &GenericAddress) -> Result<(), AcpiError> {
+        if control.load()? & SCI_ENABLED != 0 || self.smi_command == 0 || self.acpi_enable == 0 {
+            return Ok(());
+        }
+
+        Port::<u8>::new(self.smi_command as u16).write(self.acpi_enable);
+
+        for _ in 0..ENABLE_TIMEOUT_MS / 10 {
+            if control.load()? & SCI_ENABLED != 0 {
+                return Ok(());
+            }
+
+            timer::sleep_ms(10);
+        }
+
+        Err(AcpiError::Unsupported)
+    }
+}
+
+// the SLP_TYPa and SLP_TYPb values of a sleep state. they are the first two elements of a package
+// named after the state in the dsdt, which we look for in the aml instead of running it.
+fn sleep_types(name: &[u8; 4]) -> Result<(u64, u64), AcpiError> {
+    let aml = find(b"DSDT").ok_or(AcpiError::NotFound)?.data();
+
+    let start = (0..aml.len().saturating_sub(4))
+        .filter(|start| &aml[*start..*start + 4] == name)
+        .find(|start| match start {
+            0 => false,
+            1 => aml[0] == NAME_OP,
+            _ => aml[start - 1] == NAME_OP || aml[start - 2] == NAME_OP && aml[start - 1] == ROOT_PREFIX,
+        })
+        .ok_or(AcpiError::NotFound)?;
+
+    if read::<u8>(aml, start + 4)? != PACKAGE_OP {
+        return Err(AcpiError::NotFound);
+    }
+
+    // the top two bits of the package length are how many more bytes it takes up, the number of
+    // elements follows it
+    let mut offset = start + 5;
+
+    offset += (read::<u8>(aml, offset)? >> 6) as usize + 2;
+
+    let mut element = || {
+        if read::<u8>(aml, offset)? == BYTE_PREFIX {
+            offset += 1;
+        }
+
+        offset += 1;
+
+        read::<u8>(aml, offset - 1).map(|value| value as u64)
+    };
+
+    Ok((element()?, element()?))
+}
+
+// the 64 bit address of a register if the table is new enough to have one, the old i/o port
+// otherwise. a register that is not there is zero in both.
+fn register(data: &[u8], extended: usize, legacy: usize, length: usize) -> Option<GenericAddress> {
+    match GenericAddress::read(data, extended) {
+        Ok(address) if address.address != 0 => Some(address),
+        _ => {
+            let bit_width = read::<u8>(data, length).unwrap_or(0).saturating_mul(8);
+
+            read::<u32>(data, legacy).ok()
+                .filter(|port| *port != 0)
+                .map(|port| GenericAddress::port(port, bit_width))
+        },
+    }
+}
+
+pub fn parse() -> Result<Fadt, AcpiError> {
+    let data = find(b"FACP").ok_or(AcpiError::NotFound)?.data();
+
+    let flags = read::<u32>(data, Field::FLAGS)?;
+
+    let reset = match flags & RESET_SUPPORTED != 0 {
+        true => Some((GenericAddress::read(data, Field::RESET_REGISTER)?, read::<u8>(data, Field::RESET_VALUE)?)),
+        false => None,
+    };
+
+    let dsdt = match read::<u64>(data, Field::X_DSDT) {
+        Ok(dsdt) if dsdt != 0 => dsdt,
+        _ => read::<u32>(data, Field::DSDT)? as u64,
+    };
+
+    Ok(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: read(data, Field::SCI_INTERRUPT)?,
+        smi_command: read(data, Field::SMI_COMMAND)?,
+        acpi_enable: read(data, Field::ACPI_ENABLE)?,
+        pm1a_control: register(data, Field::X_PM1A_CONTROL, Field::PM1A_CONTROL, Field::PM1_CONTROL_LENGTH),
+        pm1b_control: register(data, Field::X_PM1B_CONTROL, Field::PM1B_CONTROL, Field::PM1_CONTROL_LENGTH),
+        boot_arch: read(data, Field::BOOT_ARCH)?,
+        flags,
+        reset,
+    })
+}
@@ -0,0 +1,31 @@
+use super::*;
+
+const COUNTER_64BIT: u32 = 1 << 13;
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    // the registers of the timer block, always in memory
+    pub address: PhysAddr,
+    pub comparators: u8,
+    // the main counter of the others wraps around at 32 bits
+    pub counter_64bit: bool,
+}
+
+pub fn parse() -> Result<Hpet, AcpiError> {
+    let data = find(b"HPET").ok_or(AcpiError::NotFound)?.data();
+
+    let id = read::<u32>(data, 0)?;
+    let address = GenericAddress::read(data, 4)?;
+
+    if address.space != GenericAddress::MEMORY {
+        return Err(AcpiError::InvalidSignature);
+    }
+
+    Ok(Hpet {
+        address: PhysAddr::new(address.address),
+        // bits 8..13 hold the index of the last comparator
+        comparators: ((id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: id & COUNTER_64BIT != 0,
+    })
+}
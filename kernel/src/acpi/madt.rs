@@ -0,0 +1,133 @@
+use super::*;
+
+// the flag in the madt that says there is a legacy 8259 pair that has to be masked
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[non_exhaustive]
+struct Kind;
+
+impl Kind {
+    const LOCAL_APIC: u8 = 0;
+    const IO_APIC: u8 = 1;
+    const OVERRIDE: u8 = 2;
+    const LOCAL_APIC_NMI: u8 = 4;
+    const LOCAL_APIC_ADDRESS: u8 = 5;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor: u8,
+    pub apic_id: u8,
+    pub usable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+// the polarity and trigger mode of an interrupt line, zero means whatever the bus it is on
+// conforms to.
+#[derive(Debug, Clone, Copy)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    pub fn active_low(&self) -> bool {
+        self.0 & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.0 >> 2) & 0b11 == 0b11
+    }
+}
+
+// an isa irq that is not wired to the gsi of the same number, or not active high and edge
+// triggered like isa irqs usually are.
+#[derive(Debug, Clone, Copy)]
+pub struct Override {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: Flags,
+}
+
+// a lint pin of a local apic that is wired to the nmi, processor 0xff means every processor
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    pub processor: u8,
+    pub flags: Flags,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<Override>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+pub fn parse() -> Result<Madt, AcpiError> {
+    let data = find(b"APIC").ok_or(AcpiError::NotFound)?.data();
+
+    let mut madt = Madt {
+        local_apic: PhysAddr::new(read::<u32>(data, 0)? as u64),
+        legacy_pics: read::<u32>(data, 4)? & PCAT_COMPAT != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    // a list of variable sized entries, each one starts with its kind and length
+    let mut rest = &data[8..];
+
+    while rest.len() >= 2 {
+        let length = rest[1] as usize;
+
+        if length < 2 || length > rest.len() {
+            break;
+        }
+
+        let entry = &rest[..length];
+
+        match entry[0] {
+            Kind::LOCAL_APIC => {
+                let flags = read::<u32>(entry, 4)?;
+
+                madt.local_apics.push(LocalApic {
+                    processor: read::<u8>(entry, 2)?,
+                    apic_id: read::<u8>(entry, 3)?,
+                    usable: flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0,
+                });
+            },
+            Kind::IO_APIC => madt.io_apics.push(IoApic {
+                id: read::<u8>(entry, 2)?,
+                address: PhysAddr::new(read::<u32>(entry, 4)? as u64),
+                gsi_base: read::<u32>(entry, 8)?,
+            }),
+            Kind::OVERRIDE => madt.overrides.push(Override {
+                irq: read::<u8>(entry, 3)?,
+                gsi: read::<u32>(entry, 4)?,
+                flags: Flags(read::<u16>(entry, 8)?),
+            }),
+            Kind::LOCAL_APIC_NMI => madt.nmis.push(LocalApicNmi {
+                processor: read::<u8>(entry, 2)?,
+                flags: Flags(read::<u16>(entry, 3)?),
+                lint: read::<u8>(entry, 5)?,
+            }),
+            Kind::LOCAL_APIC_ADDRESS => madt.local_apic = PhysAddr::new(read::<u64>(entry, 4)?),
+            _ => {},
+        }
+
+        rest = &rest[length..];
+    }
+
+    Ok(madt)
+}
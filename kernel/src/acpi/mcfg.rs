@@ -0,0 +1,29 @@
+use super::*;
+
+// the table starts with 8 reserved bytes, followed by one entry per pci segment group
+const ENTRIES: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+
+// a window of pcie configuration space, every function of every bus in it gets 4 KiB
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub fn parse() -> Result<Vec<Segment>, AcpiError> {
+    let data = find(b"MCFG").ok_or(AcpiError::NotFound)?.data();
+
+    data.get(ENTRIES..).ok_or(AcpiError::Truncated)?
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| Ok(Segment {
+            base: PhysAddr::new(read(entry, 0)?),
+            segment: read(entry, 8)?,
+            start_bus: read(entry, 10)?,
+            end_bus: read(entry, 11)?,
+        }))
+        .collect()
+}
@@ -0,0 +1,290 @@
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use crate::vmm::{self, VmmError};
+use crate::{allocator, debug};
+
+use limine::response::RsdpResponse;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+use spin::Mutex;
+
+use alloc::vec::Vec;
+
+use core::{mem, ptr, slice};
+
+// every table the rsdt or xsdt points at that passed its checksum
+static mut TABLES: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidSignature,
+    InvalidChecksum,
+    NotFound,
+    // a table or one of its entries ends before a field it is supposed to have
+    Truncated,
+    // the register lives somewhere we can not reach, or the firmware did not let go of it
+    Unsupported,
+    Vmm(VmmError),
+}
+
+impl From<VmmError> for AcpiError {
+    fn from(err: VmmError) -> AcpiError {
+        AcpiError::Vmm(err)
+    }
+}
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // everything from here on only exists since acpi 2.0
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// the header every system description table starts with
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    // the bytes following the header
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const SdtHeader as *const u8).add(mem::size_of::<SdtHeader>()),
+                self.length as usize - mem::size_of::<SdtHeader>(),
+            )
+        }
+    }
+}
+
+// the way acpi points at registers, which may live in memory or in i/o space
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const MEMORY: u8 = 0;
+    pub const IO: u8 = 1;
+
+    // the 12 bytes of a generic address structure at `offset`
+    fn read(bytes: &[u8], offset: usize) -> Result<GenericAddress, AcpiError> {
+        Ok(GenericAddress {
+            space: read(bytes, offset)?,
+            bit_width: read(bytes, offset + 1)?,
+            bit_offset: read(bytes, offset + 2)?,
+            access_size: read(bytes, offset + 3)?,
+            address: read(bytes, offset + 4)?,
+        })
+    }
+
+    // an i/o port from one of the 32 bit fields that predate generic addresses
+    fn port(port: u32, bit_width: u8) -> GenericAddress {
+        GenericAddress {
+            space: GenericAddress::IO,
+            bit_width,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        }
+    }
+
+    pub fn is_io(&self) -> bool {
+        self.space == GenericAddress::IO
+    }
+
+    // the size of a single access in bytes, old tables leave it to the width of the register
+    fn access_width(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => (self.bit_width / 8).next_power_of_two().clamp(1, 8),
+        }
+    }
+
+    pub unsafe fn load(&self) -> Result<u64, AcpiError> {
+        let value = match (self.is_io(), self.access_width()) {
+            (true, 1) => Port::<u8>::new(self.address as u16).read() as u64,
+            (true, 2) => Port::<u16>::new(self.address as u16).read() as u64,
+            (true, 4) => Port::<u32>::new(self.address as u16).read() as u64,
+            (false, width) if self.space == GenericAddress::MEMORY => {
+                let virt = vmm::map_physical(PhysAddr::new(self.address), width as u64, PageTableFlags::NO_CACHE)?;
+
+                match width {
+                    1 => ptr::read_volatile(virt.as_ptr::<u8>()) as u64,
+                    2 => ptr::read_volatile(virt.as_ptr::<u16>()) as u64,
+                    4 => ptr::read_volatile(virt.as_ptr::<u32>()) as u64,
+                    _ => ptr::read_volatile(virt.as_ptr::<u64>()),
+                }
+            },
+            _ => return Err(AcpiError::Unsupported),
+        };
+
+        Ok(value >> self.bit_offset)
+    }
+
+    pub unsafe fn store(&self, value: u64) -> Result<(), AcpiError> {
+        let value = value << self.bit_offset;
+
+        match (self.is_io(), self.access_width()) {
+            (true, 1) => Port::<u8>::new(self.address as u16).write(value as u8),
+            (true, 2) => Port::<u16>::new(self.address as u16).write(value as u16),
+            (true, 4) => Port::<u32>::new(self.address as u16).write(value as u32),
+            (false, width) if self.space == GenericAddress::MEMORY => {
+                let virt = vmm::map_physical(PhysAddr::new(self.address), width as u64, PageTableFlags::NO_CACHE)?;
+
+                match width {
+                    1 => ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+                    2 => ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+                    4 => ptr::write_volatile(virt.as_mut_ptr::<u32>(), value as u32),
+                    _ => ptr::write_volatile(virt.as_mut_ptr::<u64>(), value),
+                }
+            },
+            _ => return Err(AcpiError::Unsupported),
+        }
+
+        Ok(())
+    }
+}
+
+// a field of a table, which has no alignment guarantees whatsoever
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Result<T, AcpiError> {
+    match offset + mem::size_of::<T>() <= bytes.len() {
+        true => Ok(unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const T) }),
+        false => Err(AcpiError::Truncated),
+    }
+}
+
+// all bytes of a valid table add up to zero
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+// firmware tables are not necessarily covered by the hhdm, so they get mapped before we look at them
+unsafe fn map<T>(addr: PhysAddr, size: usize) -> Result<&'static T, AcpiError> {
+    let virt = vmm::map_physical(addr, size as u64, PageTableFlags::empty())?;
+
+    Ok(&*virt.as_ptr::<T>())
+}
+
+unsafe fn table(addr: PhysAddr) -> Result<&'static SdtHeader, AcpiError> {
+    let header = map::<SdtHeader>(addr, mem::size_of::<SdtHeader>())?;
+    let length = header.length as usize;
+
+    if length < mem::size_of::<SdtHeader>() {
+        return Err(AcpiError::InvalidSignature);
+    }
+
+    map::<u8>(addr, length)?;
+
+    match checksum(slice::from_raw_parts(header as *const SdtHeader as *const u8, length)) {
+        true => Ok(header),
+        false => Err(AcpiError::InvalidChecksum),
+    }
+}
+
+// validates the rsdp limine found and collects the tables of the rsdt, or of the xsdt if there is
+// one. returns the number of tables that passed their checksum.
+pub fn init(rsdp: Option<&RsdpResponse>) -> Result<usize, AcpiError> {
+    let addr = rsdp.ok_or(AcpiError::NoRsdp)?.address() as u64;
+    let hhdm = unsafe { allocator::HHDM };
+
+    // older base revisions hand out the rsdp through the hhdm
+    let addr = PhysAddr::new(if addr >= hhdm { addr - hhdm } else { addr });
+
+    let (root, entry_size) = unsafe {
+        let rsdp = map::<Rsdp>(addr, mem::size_of::<Rsdp>())?;
+
+        if &rsdp.signature != b"RSD PTR " {
+            return Err(AcpiError::InvalidSignature);
+        }
+
+        let bytes = slice::from_raw_parts(rsdp as *const Rsdp as *const u8, mem::size_of::<Rsdp>());
+
+        if !checksum(&bytes[..20]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+
+        match rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            true if checksum(bytes) => (PhysAddr::new(rsdp.xsdt_address), 8),
+            true => return Err(AcpiError::InvalidChecksum),
+            false => (PhysAddr::new(rsdp.rsdt_address as u64), 4),
+        }
+    };
+
+    let root = unsafe { table(root)? };
+
+    let entries = root.data().chunks_exact(entry_size).map(|entry| match entry_size {
+        8 => unsafe { ptr::read_unaligned(entry.as_ptr() as *const u64) },
+        _ => unsafe { ptr::read_unaligned(entry.as_ptr() as *const u32) as u64 },
+    });
+
+    for entry in entries.map(PhysAddr::new) {
+        add(entry);
+    }
+
+    // the dsdt is not listed in the root table, only the fadt knows where it is
+    if let Ok(fadt) = fadt::parse() {
+        add(fadt.dsdt);
+
+        debug::write(format_args!("[debug] sci irq {}, pm1a control {:x?}\n", fadt.sci_interrupt, fadt.pm1a_control));
+    }
+
+    if let Ok(hpet) = hpet::parse() {
+        debug::write(format_args!("[debug] hpet at {:x?} with {} comparators\n", hpet.address, hpet.comparators));
+    }
+
+    if let Ok(segments) = mcfg::parse() {
+        for segment in segments {
+            debug::write(format_args!("[debug] pcie segment {} at {:x?}, buses {}..={}\n", segment.segment, segment.base, segment.start_bus, segment.end_bus));
+        }
+    }
+
+    Ok(unsafe { TABLES.lock().len() })
+}
+
+fn add(addr: PhysAddr) {
+    match unsafe { table(addr) } {
+        Ok(header) => {
+            debug::write(format_args!("[debug] acpi table {} at {:x?}\n", core::str::from_utf8(&header.signature).unwrap_or("????"), addr));
+
+            unsafe { TABLES.lock().push(addr); }
+        },
+        Err(err) => debug::write(format_args!("[debug] skipping acpi table at {:x?}: {:?}\n", addr, err)),
+    }
+}
+
+// the first table with `signature`, they are all mapped by init already
+pub fn find(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    unsafe {
+        TABLES.lock().iter()
+            .map(|addr| &*allocator::phys_to_virt(*addr).as_ptr::<SdtHeader>())
+            .find(|header| &header.signature == signature)
+    }
+}
@@ -0,0 +1,204 @@
+use crate::debug;
+
+use limine::memory_map::EntryType;
+use limine::response::MemoryMapResponse;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame};
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use spin::Mutex;
+
+use core::ptr;
+
+pub static FRAMES: Mutex<Frames> = Mutex::new(Frames::new());
+
+pub const FRAME_SIZE: u64 = 4096;
+
+
+// every bit in the bitmap represents one 4 KiB frame, a set bit means the frame is in use.
+// larger frames are handed out as aligned runs of 4 KiB frames.
+pub struct Frames {
+    bitmap: *mut u64,
+    length: usize,
+    free: usize,
+    next: usize,
+}
+
+unsafe impl Send for Frames {}
+
+impl Frames {
+    pub const fn new() -> Frames {
+        Frames {
+            bitmap: ptr::null_mut(),
+            length: 0,
+            free: 0,
+            next: 0,
+        }
+    }
+
+    #[inline]
+    fn words(&self) -> usize {
+        self.length.div_ceil(64)
+    }
+
+    #[inline]
+    fn is_used(&self, index: usize) -> bool {
+        unsafe { *self.bitmap.add(index / 64) & (1 << (index % 64)) != 0 }
+    }
+
+    #[inline]
+    fn set(&mut self, index: usize) {
+        if !self.is_used(index) {
+            unsafe { *self.bitmap.add(index / 64) |= 1 << (index % 64); }
+
+            self.free -= 1;
+        }
+    }
+
+    #[inline]
+    fn clear(&mut self, index: usize) {
+        if self.is_used(index) {
+            unsafe { *self.bitmap.add(index / 64) &= !(1 << (index % 64)); }
+
+            self.free += 1;
+        }
+    }
+
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub unsafe fn init(&mut self, memory_map: &MemoryMapResponse, hhdm: u64) {
+        let usable = || memory_map.entries().iter().filter(|entry| entry.entry_type == EntryType::USABLE);
+
+        let top = usable().map(|entry| entry.base + entry.length).max().unwrap_or(0);
+
+        self.length = (top / FRAME_SIZE) as usize;
+
+        let size = (self.words() * 8) as u64;
+
+        let entry = usable()
+            .find(|entry| entry.length >= size)
+            .expect("no memory region is large enough for the frame bitmap");
+
+        self.bitmap = (entry.base + hhdm) as *mut u64;
+
+        // everything starts out as used and only the usable regions are released, this way holes in
+        // the memory map can never be handed out.
+        ptr::write_bytes(self.bitmap, 0xff, self.words());
+
+        for entry in usable() {
+            let start = entry.base.div_ceil(FRAME_SIZE) as usize;
+            let end = ((entry.base + entry.length) / FRAME_SIZE) as usize;
+
+            for index in start..end {
+                *self.bitmap.add(index / 64) &= !(1 << (index % 64));
+            }
+
+            self.free += end.saturating_sub(start);
+        }
+
+        let start = (entry.base / FRAME_SIZE) as usize;
+
+        for index in start..start + size.div_ceil(FRAME_SIZE) as usize {
+            self.set(index);
+        }
+
+        // a frame at physical address zero is indistinguishable from a missing one
+        if self.length > 0 {
+            self.set(0);
+        }
+    }
+
+    // finds `count` contiguous free frames starting at a frame index that is a multiple of `align`
+    pub fn allocate(&mut self, count: usize, align: usize) -> Option<PhysAddr> {
+        let slots = self.length / align;
+        let first = self.next.div_ceil(align);
+
+        if self.free < count || slots == 0 {
+            return None;
+        }
+
+        let found = (0..slots)
+            .map(|slot| (first + slot) % slots * align)
+            .find(|index| {
+                *index + count <= self.length && (*index..*index + count).all(|index| !self.is_used(index))
+            })?;
+
+        for index in found..found + count {
+            self.set(index);
+        }
+
+        self.next = found + count;
+
+        Some(PhysAddr::new(found as u64 * FRAME_SIZE))
+    }
+
+    pub fn deallocate(&mut self, addr: PhysAddr, count: usize) {
+        let start = (addr.as_u64() / FRAME_SIZE) as usize;
+
+        for index in start..start + count {
+            self.clear(index);
+        }
+
+        self.next = self.next.min(start);
+    }
+}
+
+// zero sized handle to the global frame allocator so it can be passed to the paging structures
+// of the x86_64 crate without holding the lock for longer than a single allocation.
+pub struct FrameSource;
+
+unsafe impl<S: PageSize> FrameAllocator<S> for FrameSource {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        alloc()
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for FrameSource {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        dealloc(frame);
+    }
+}
+
+pub fn alloc<S: PageSize>() -> Option<PhysFrame<S>> {
+    let count = (S::SIZE / FRAME_SIZE) as usize;
+
+    interrupts::without_interrupts(|| {
+        FRAMES.lock()
+            .allocate(count, count)
+            .map(|addr| PhysFrame::containing_address(addr))
+    })
+}
+
+pub fn dealloc<S: PageSize>(frame: PhysFrame<S>) {
+    interrupts::without_interrupts(|| {
+        FRAMES.lock().deallocate(frame.start_address(), (S::SIZE / FRAME_SIZE) as usize);
+    });
+}
+
+// contiguous physical memory for things like dma buffers, the result is aligned to 4 KiB.
+pub fn alloc_contiguous(size: u64) -> Option<PhysAddr> {
+    interrupts::without_interrupts(|| {
+        FRAMES.lock().allocate(size.div_ceil(FRAME_SIZE) as usize, 1)
+    })
+}
+
+pub fn dealloc_contiguous(addr: PhysAddr, size: u64) {
+    interrupts::without_interrupts(|| {
+        FRAMES.lock().deallocate(addr, size.div_ceil(FRAME_SIZE) as usize);
+    });
+}
+
+pub fn init(memory_map: &MemoryMapResponse, hhdm: u64) {
+    let mut frames = FRAMES.lock();
+
+    unsafe {
+        frames.init(memory_map, hhdm);
+    }
+
+    debug::write(format_args!("[debug] frames: {} free of {}\n", frames.free(), frames.length()));
+}
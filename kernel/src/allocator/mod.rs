@@ -1,18 +1,24 @@
+pub mod frame;
+
 use crate::debug;
 
-use limine::memory_map::{Entry, EntryType};
 use limine::response::{HhdmResponse, MemoryMapResponse};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
 use spin::Mutex;
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use core::mem;
 use core::cmp::Ordering;
 use core::slice;
 
 const CHUNK_LIMIT: usize = 100;
+const HEAP_SIZE: u64 = 0x2000000;
 
 #[global_allocator]
 pub static mut ALLOC: Allocator = Allocator::new();
+pub static mut HHDM: u64 = 0;
 
 
 #[derive(Debug, Clone, Copy)]
@@ -60,7 +66,7 @@ impl Allocator {
     pub unsafe fn merge(&self) {
         let mut index = 1;
 
-        while !(*self.chunks)[index].is_empty() && index < CHUNK_LIMIT - 1 {
+        while index < CHUNK_LIMIT && !(*self.chunks)[index].is_empty() {
             if (*self.chunks)[index].base + (*self.chunks)[index].length == (*self.chunks)[index - 1].base {
                 (*self.chunks)[index].length += (*self.chunks)[index - 1].length;
 
@@ -73,10 +79,27 @@ impl Allocator {
         }
     }
 
+    // a full table is merged to make room first, a chunk that still does not fit would be lost for
+    // good, so that is as fatal as running out of memory.
     pub fn push(&self, new: Chunk) {
-        self.map(|chunk| {
-            chunk.is_empty().then(|| { *chunk = new; true }).unwrap_or(false)
-        });
+        if self.insert(new) {
+            return;
+        }
+
+        unsafe { self.cleanup(); }
+
+        if !self.insert(new) {
+            panic!("ran out of memory chunks");
+        }
+    }
+
+    fn insert(&self, new: Chunk) -> bool {
+        unsafe {
+            (*self.chunks).iter_mut()
+                .find(|chunk| chunk.is_empty())
+                .map(|chunk| *chunk = new)
+                .is_some()
+        }
     }
 
     pub fn largest(&self) -> Option<usize> {
@@ -97,10 +120,8 @@ impl Allocator {
 
 unsafe impl Sync for Allocator {}
 
-unsafe impl GlobalAlloc for Allocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.lock.lock();
-
+impl Allocator {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         if let Some(index) = self.largest() {
             if (*self.chunks)[index].length < layout.size() as u64 {
                 panic!("not enough memory, buy more ram :)");
@@ -110,14 +131,15 @@ unsafe impl GlobalAlloc for Allocator {
 
             let offset = ((*self.chunks)[index].base + (*self.chunks)[index].length) % layout.align() as u64;
 
-            if offset != 0 {
-                (*self.chunks)[index].length -= offset;
-
-                self.push(Chunk::new((*self.chunks)[index].base + (*self.chunks)[index].length + layout.size() as u64, offset));
-            }
+            (*self.chunks)[index].length -= offset;
 
             let addr = (*self.chunks)[index].base + (*self.chunks)[index].length;
 
+            // pushing may reorder the table, so `index` is not to be used past this
+            if offset != 0 {
+                self.push(Chunk::new(addr + layout.size() as u64, offset));
+            }
+
             self.cleanup();
 
             /*
@@ -134,9 +156,7 @@ unsafe impl GlobalAlloc for Allocator {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock.lock();
-
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         self.push(Chunk::new(ptr as u64, layout.size() as u64));
 
         self.cleanup();
@@ -151,29 +171,50 @@ unsafe impl GlobalAlloc for Allocator {
     }
 }
 
+// the guard has to live for the whole operation, and an interrupt handler that allocates must not
+// run into a lock held by the code it interrupted.
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let _lock = self.lock.lock();
+
+            self.allocate(layout)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            let _lock = self.lock.lock();
+
+            self.deallocate(ptr, layout);
+        })
+    }
+}
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    unsafe { VirtAddr::new(addr.as_u64() + HHDM) }
+}
+
 pub fn init(memory_map: &MemoryMapResponse, hhdm: &HhdmResponse) {
-    let entries = memory_map
-        .entries()
-        .iter()
-        .filter(|entry| entry.entry_type == EntryType::USABLE)
-        .map(|entry| Entry {
-            base: entry.base + hhdm.offset(),
-            length: entry.length,
-            entry_type: entry.entry_type,
-        });
+    unsafe {
+        HHDM = hhdm.offset();
+    }
+
+    frame::init(memory_map, hhdm.offset());
+
+    // the heap is carved out of the frame allocator as one contiguous region, that way the frames
+    // handed out for paging and stacks never overlap with heap memory.
+    let heap = frame::alloc_contiguous(HEAP_SIZE).expect("failed to allocate memory for the heap");
+
+    let base = phys_to_virt(heap).as_u64();
+    let reserved = mem::size_of::<[Chunk; CHUNK_LIMIT]>() as u64;
 
     unsafe {
-        for entry in entries {
-            if ALLOC.chunks.is_null() {
-                *(entry.base as *mut [Chunk; CHUNK_LIMIT]) = [Chunk::new(0, 0); CHUNK_LIMIT];
+        *(base as *mut [Chunk; CHUNK_LIMIT]) = [Chunk::new(0, 0); CHUNK_LIMIT];
 
-                ALLOC.chunks = entry.base as *mut [Chunk; CHUNK_LIMIT];
-            } else {
-                ALLOC.push(Chunk::new(entry.base, entry.length));
-            }
-        }
+        ALLOC.chunks = base as *mut [Chunk; CHUNK_LIMIT];
 
-        slice::sort::quicksort(ALLOC.chunks.as_mut_unchecked(), |a, b| a.base > b.base);
+        ALLOC.push(Chunk::new(base + reserved, HEAP_SIZE - reserved));
 
         ALLOC.map(|chunk| {
             debug::write(format_args!("[debug] {:x?}\n", chunk));
@@ -182,5 +223,3 @@ pub fn init(memory_map: &MemoryMapResponse, hhdm: &HhdmResponse) {
         });
     }
 }
-
-
@@ -0,0 +1,153 @@
+use crate::acpi::madt::{Flags, Madt, Override};
+use crate::{debug, vmm};
+use super::ApicError;
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use spin::Mutex;
+
+use alloc::vec::Vec;
+
+use core::ptr;
+
+static mut IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+// the isa irqs the firmware wired differently from the identity mapping
+static mut OVERRIDES: Vec<Override> = Vec::new();
+
+
+#[non_exhaustive]
+struct Register;
+
+impl Register {
+    const SELECT: u64 = 0x00;
+    const WINDOW: u64 = 0x10;
+
+    const VERSION: u32 = 0x01;
+    const REDIRECTION: u32 = 0x10;
+}
+
+#[non_exhaustive]
+struct Redirection;
+
+impl Redirection {
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+}
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    count: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + Register::SELECT) as *mut u32, register);
+            ptr::read_volatile((self.base + Register::WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + Register::SELECT) as *mut u32, register);
+            ptr::write_volatile((self.base + Register::WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.count).contains(&gsi)
+    }
+
+    // the high half goes first so the entry is never unmasked with a stale destination
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = Register::REDIRECTION + (gsi - self.gsi_base) * 2;
+
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+// maps every io apic of the madt and masks all of their inputs
+pub fn init(madt: &Madt) -> Result<(), ApicError> {
+    let flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+    let mut io_apics = unsafe { IO_APICS.lock() };
+
+    for entry in madt.io_apics.iter() {
+        let base = vmm::map_physical(entry.address, 0x20, flags)?;
+
+        let mut io_apic = IoApic {
+            base: base.as_u64(),
+            gsi_base: entry.gsi_base,
+            count: 0,
+        };
+
+        // bits 16..24 hold the index of the last redirection entry
+        io_apic.count = ((io_apic.read(Register::VERSION) >> 16) & 0xff) + 1;
+
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.count {
+            io_apic.set_redirection(gsi, Redirection::MASKED);
+        }
+
+        debug::write(format_args!(
+            "[debug] io apic {} at {:x?}, gsis {}..{}\n",
+            entry.id, entry.address, io_apic.gsi_base, io_apic.gsi_base + io_apic.count,
+        ));
+
+        io_apics.push(io_apic);
+    }
+
+    unsafe {
+        OVERRIDES = madt.overrides.clone();
+    }
+
+    Ok(())
+}
+
+// the gsi an isa irq arrives at and how its line behaves
+fn resolve(irq: u8) -> (u32, Flags) {
+    unsafe { OVERRIDES.iter() }
+        .find(|entry| entry.irq == irq)
+        .map(|entry| (entry.gsi, entry.flags))
+        .unwrap_or((irq as u32, Flags(0)))
+}
+
+fn with_io_apic<F>(gsi: u32, f: F) -> Result<(), ApicError> where F: FnOnce(&IoApic) {
+    interrupts::without_interrupts(|| {
+        let io_apics = unsafe { IO_APICS.lock() };
+
+        let io_apic = io_apics.iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(ApicError::NoIoApic(gsi))?;
+
+        f(io_apic);
+
+        Ok(())
+    })
+}
+
+// delivers the isa `irq` as `vector` to the local apic with `lapic_id`
+pub fn route(irq: u8, vector: u8, lapic_id: u32) -> Result<(), ApicError> {
+    let (gsi, flags) = resolve(irq);
+
+    let mut entry = vector as u64 | (lapic_id as u64) << 56;
+
+    if flags.active_low() {
+        entry |= Redirection::ACTIVE_LOW;
+    }
+
+    if flags.level_triggered() {
+        entry |= Redirection::LEVEL_TRIGGERED;
+    }
+
+    with_io_apic(gsi, |io_apic| io_apic.set_redirection(gsi, entry))
+}
+
+pub fn mask(irq: u8) -> Result<(), ApicError> {
+    let (gsi, _) = resolve(irq);
+
+    with_io_apic(gsi, |io_apic| io_apic.set_redirection(gsi, Redirection::MASKED))
+}
@@ -0,0 +1,179 @@
+use super::Register;
+use crate::cpu::{self, Cpu};
+use crate::interrupt::Vector;
+
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+use spin::Mutex;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use core::hint;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+type Call = Box<dyn FnOnce() + Send>;
+
+// only one shootdown is in flight at a time, the request itself is read from nmi context where
+// no lock can be taken.
+static mut SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+// the root of the address space the page belongs to, zero for the kernel half
+static FLUSH_ROOT: AtomicU64 = AtomicU64::new(0);
+static FLUSH_ADDR: AtomicU64 = AtomicU64::new(0);
+
+
+#[non_exhaustive]
+struct Icr;
+
+impl Icr {
+    const NMI: u32 = 0b100 << 8;
+    const PENDING: u32 = 1 << 12;
+    const ASSERT: u32 = 1 << 14;
+}
+
+// the messages a cpu can receive from the others
+pub struct Mailbox {
+    // set once the local apic of the cpu is up, nothing is sent to it before that
+    ready: AtomicBool,
+    // a tlb shootdown this cpu still has to acknowledge
+    flush: AtomicBool,
+    calls: Mutex<VecDeque<Call>>,
+}
+
+impl Mailbox {
+    pub const fn new() -> Mailbox {
+        Mailbox {
+            ready: AtomicBool::new(false),
+            flush: AtomicBool::new(false),
+            calls: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+// marks the calling cpu as ready to receive ipis, has to come after its local apic is set up
+pub fn init_local() {
+    if super::is_enabled() {
+        cpu::current().mailbox.ready.store(true, Ordering::Release);
+    }
+}
+
+fn send(target: &Cpu, command: u32) {
+    interrupts::without_interrupts(|| {
+        // the icr takes one command at a time
+        while super::read(Register::ICR_LOW) & Icr::PENDING != 0 {
+            hint::spin_loop();
+        }
+
+        super::write(Register::ICR_HIGH, target.lapic_id << 24);
+        super::write(Register::ICR_LOW, command | Icr::ASSERT);
+    });
+}
+
+// every other cpu that takes ipis
+fn others() -> impl Iterator<Item = &'static &'static Cpu> {
+    let this = cpu::current().index;
+
+    cpu::all().iter().filter(move |cpu| cpu.index != this && cpu.mailbox.ready.load(Ordering::Acquire))
+}
+
+// makes `target` look at its run queue again, which wakes it up if it is idle
+pub fn reschedule(target: &Cpu) {
+    if target.mailbox.ready.load(Ordering::Acquire) {
+        send(target, Vector::RESCHEDULE as u32);
+    }
+}
+
+// runs `f` on `target` from its call function interrupt, without waiting for it to happen
+pub fn call<F>(target: &Cpu, f: F) where F: FnOnce() + Send + 'static {
+    if target.index == cpu::current().index {
+        interrupts::without_interrupts(f);
+    } else {
+        interrupts::without_interrupts(|| target.mailbox.calls.lock().push_back(Box::new(f)));
+
+        send(target, Vector::CALL_FUNCTION as u32);
+    }
+}
+
+// parks every other cpu for good, for when the kernel goes down
+pub fn stop_others() {
+    if !super::is_enabled() {
+        return;
+    }
+
+    for cpu in others() {
+        call(cpu, || crate::halt());
+    }
+}
+
+// called from the call function interrupt, the lock is only held to take a call off the queue
+pub fn handle_calls() {
+    loop {
+        let call = interrupts::without_interrupts(|| cpu::current().mailbox.calls.lock().pop_front());
+
+        match call {
+            Some(call) => call(),
+            None => break,
+        }
+    }
+}
+
+// drops the translation of `addr` on every other cpu and waits until they are all done. `root` is
+// the address space the page belongs to, or none for the kernel half that every cpu shares.
+//
+// whoever changes a mapping usually holds a lock with interrupts disabled that the other cpus may
+// be spinning on just as well, so the shootdown is sent as an nmi which gets through anyway.
+pub fn flush_tlb(root: Option<u64>, addr: VirtAddr) {
+    if !super::is_enabled() || cpu::online() < 2 {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let _guard = unsafe { SHOOTDOWN.lock() };
+
+        FLUSH_ROOT.store(root.unwrap_or(0), Ordering::SeqCst);
+        FLUSH_ADDR.store(addr.as_u64(), Ordering::SeqCst);
+
+        for cpu in others() {
+            cpu.mailbox.flush.store(true, Ordering::SeqCst);
+
+            send(cpu, Icr::NMI);
+        }
+
+        for cpu in others() {
+            while cpu.mailbox.flush.load(Ordering::SeqCst) {
+                hint::spin_loop();
+            }
+        }
+    });
+}
+
+// called first thing from the nmi handler, returns whether the nmi was a shootdown. the nmi may
+// hit while gs still belongs to ring 3, so the cpu is found through its apic id instead.
+pub fn handle_nmi() -> bool {
+    if !super::is_enabled() {
+        return false;
+    }
+
+    let id = super::id();
+
+    let Some(cpu) = cpu::all().iter().find(|cpu| cpu.lapic_id == id) else {
+        return false;
+    };
+
+    if !cpu.mailbox.flush.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let root = FLUSH_ROOT.load(Ordering::SeqCst);
+
+    // a lower half that is not active here is flushed by the next cr3 switch anyway
+    if root == 0 || Cr3::read().0.start_address().as_u64() == root {
+        tlb::flush(VirtAddr::new(FLUSH_ADDR.load(Ordering::SeqCst)));
+    }
+
+    cpu.mailbox.flush.store(false, Ordering::SeqCst);
+
+    true
+}
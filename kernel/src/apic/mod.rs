@@ -0,0 +1,179 @@
+pub mod ioapic;
+pub mod ipi;
+
+use crate::acpi::{madt::{LocalApicNmi, Madt}, AcpiError};
+use crate::vmm::{self, VmmError};
+use crate::debug;
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+
+use alloc::vec::Vec;
+
+use core::ptr;
+
+// where the registers of the local apic are mapped, every cpu sees its own apic at the same
+// address. zero until init found one.
+static mut BASE: u64 = 0;
+
+// the lint pins the madt wired to the nmi along with the apic id they belong to, none means every
+// apic. each cpu programs its own apic from this.
+static mut NMIS: Vec<(Option<u32>, LocalApicNmi)> = Vec::new();
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// interrupts the apic raises without anything to deliver, they must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+
+#[non_exhaustive]
+pub struct Register;
+
+impl Register {
+    pub const ID: u32 = 0x20;
+    pub const TASK_PRIORITY: u32 = 0x80;
+    pub const EOI: u32 = 0xb0;
+    pub const SPURIOUS: u32 = 0xf0;
+    pub const ERROR_STATUS: u32 = 0x280;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const TIMER_INITIAL: u32 = 0x380;
+    pub const TIMER_CURRENT: u32 = 0x390;
+    pub const TIMER_DIVIDE: u32 = 0x3e0;
+}
+
+#[non_exhaustive]
+pub struct Lvt;
+
+impl Lvt {
+    pub const MASKED: u32 = 1 << 16;
+    pub const NMI: u32 = 0b100 << 8;
+    pub const ACTIVE_LOW: u32 = 1 << 13;
+    pub const PERIODIC: u32 = 1 << 17;
+    pub const TSC_DEADLINE: u32 = 0b10 << 17;
+}
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+
+#[derive(Debug)]
+pub enum ApicError {
+    Acpi(AcpiError),
+    Vmm(VmmError),
+    // no io apic handles this gsi
+    NoIoApic(u32),
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> ApicError {
+        match err {
+            AcpiError::Vmm(err) => ApicError::Vmm(err),
+            err => ApicError::Acpi(err),
+        }
+    }
+}
+
+impl From<VmmError> for ApicError {
+    fn from(err: VmmError) -> ApicError {
+        ApicError::Vmm(err)
+    }
+}
+
+pub fn read(register: u32) -> u32 {
+    unsafe { ptr::read_volatile((BASE + register as u64) as *const u32) }
+}
+
+pub fn write(register: u32, value: u32) {
+    unsafe { ptr::write_volatile((BASE + register as u64) as *mut u32, value) }
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { BASE != 0 }
+}
+
+// the id of the local apic of the calling cpu
+pub fn id() -> u32 {
+    read(Register::ID) >> 24
+}
+
+// acknowledges the interrupt that is being handled on the calling cpu
+pub fn eoi() {
+    write(Register::EOI, 0);
+}
+
+// maps the local apic registers and the io apics the madt describes, then sets up the apic of the
+// boot processor. the 8259 stays untouched, masking it is up to the caller.
+pub fn init(madt: &Madt) -> Result<(), ApicError> {
+    // device registers must not be cached
+    let flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+    let base = vmm::map_physical(madt.local_apic, 0x1000, flags)?;
+
+    ioapic::init(madt)?;
+
+    unsafe {
+        // the madt names processors by their acpi id, the apic only knows its apic id
+        NMIS = madt.nmis.iter()
+            .filter_map(|nmi| match nmi.processor {
+                0xff => Some((None, *nmi)),
+                processor => madt.local_apics.iter()
+                    .find(|apic| apic.processor == processor)
+                    .map(|apic| (Some(apic.apic_id as u32), *nmi)),
+            })
+            .collect();
+
+        BASE = base.as_u64();
+    }
+
+    init_local();
+
+    debug::write(format_args!(
+        "[debug] local apic at {:x?}, {} io apics, {} cpus in the madt\n",
+        madt.local_apic, madt.io_apics.len(), madt.local_apics.iter().filter(|apic| apic.usable).count(),
+    ));
+
+    Ok(())
+}
+
+// enables the local apic of the calling cpu with nothing but its nmi pins unmasked
+pub fn init_local() {
+    unsafe {
+        let mut msr = Msr::new(APIC_BASE_MSR);
+
+        msr.write(msr.read() | APIC_BASE_ENABLE);
+    }
+
+    write(Register::TASK_PRIORITY, 0);
+
+    write(Register::LVT_TIMER, Lvt::MASKED);
+    write(Register::LVT_LINT0, Lvt::MASKED);
+    write(Register::LVT_LINT1, Lvt::MASKED);
+    write(Register::LVT_ERROR, Lvt::MASKED);
+
+    let id = id();
+
+    for (_, nmi) in unsafe { NMIS.iter() }.filter(|(apic_id, _)| apic_id.map_or(true, |apic_id| apic_id == id)) {
+        let mut lvt = Lvt::NMI;
+
+        if nmi.flags.active_low() {
+            lvt |= Lvt::ACTIVE_LOW;
+        }
+
+        match nmi.lint {
+            0 => write(Register::LVT_LINT0, lvt),
+            1 => write(Register::LVT_LINT1, lvt),
+            _ => {},
+        }
+    }
+
+    write(Register::SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+
+    // the error status has to be written before it can be read, clear whatever firmware left
+    write(Register::ERROR_STATUS, 0);
+
+    eoi();
+}
@@ -0,0 +1,160 @@
+use crate::apic::ipi::Mailbox;
+use crate::gdt::{self, Tables};
+use crate::scheduler::{self, queue::RunQueue, Scheduler};
+use crate::{debug, fpu, interrupt, timer, vmm};
+
+use limine::response::SmpResponse;
+use limine::smp;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+use spin::Mutex;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::arch::asm;
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// every cpu with the boot processor first, filled in before any other cpu is started and never
+// touched again afterwards.
+static mut CPUS: Vec<&'static Cpu> = Vec::new();
+
+// the number of cpus that made it into the scheduler
+static mut ONLINE: Mutex<usize> = Mutex::new(0);
+
+
+// the data every cpu keeps for itself, a cpu finds its own through the gs base
+#[repr(C)]
+pub struct Cpu {
+    // has to stay the first field, gs:0 is how current finds the struct
+    this: *const Cpu,
+    pub index: usize,
+    pub lapic_id: u32,
+    pub tables: Tables,
+    pub scheduler: Mutex<Scheduler>,
+    // the ready threads of this cpu, this lock is always taken last so anyone may push to it
+    pub queue: Mutex<RunQueue>,
+    pub mailbox: Mailbox,
+    // set while the idle task has nothing to do, threads that get ready elsewhere go here first
+    idle: AtomicBool,
+}
+
+unsafe impl Send for Cpu {}
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    fn new(index: usize, lapic_id: u32) -> &'static Cpu {
+        let cpu = Box::leak(Box::new(Cpu {
+            this: ptr::null(),
+            index,
+            lapic_id,
+            tables: Tables::new(),
+            scheduler: Mutex::new(Scheduler::new()),
+            queue: Mutex::new(RunQueue::new()),
+            mailbox: Mailbox::new(),
+            idle: AtomicBool::new(false),
+        }));
+
+        cpu.this = cpu;
+
+        cpu
+    }
+
+    // makes this the cpu that current returns on the calling cpu
+    fn load(&'static self) {
+        GsBase::write(VirtAddr::new(self as *const Cpu as u64));
+
+        // ring 3 gets a gs of its own, the trap entry swaps the two on every switch between rings
+        KernelGsBase::write(VirtAddr::zero());
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Acquire)
+    }
+
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Release);
+    }
+}
+
+// the per cpu data of the calling cpu
+pub fn current() -> &'static Cpu {
+    unsafe {
+        let cpu: *const Cpu;
+
+        asm!("mov {}, gs:0", out(reg) cpu, options(nostack, readonly, preserves_flags));
+
+        &*cpu
+    }
+}
+
+pub fn all() -> &'static [&'static Cpu] {
+    unsafe { CPUS.as_slice() }
+}
+
+pub fn online() -> usize {
+    unsafe { *ONLINE.lock() }
+}
+
+// sets up the per cpu data of every cpu limine found, without an smp response the boot processor
+// is the only one. has to run before anything that needs to know the current cpu.
+pub fn init(smp: Option<&SmpResponse>) {
+    let bsp = smp.map(|smp| smp.bsp_lapic_id()).unwrap_or(0);
+
+    let others = smp.iter()
+        .flat_map(|smp| smp.cpus().iter())
+        .map(|cpu| cpu.lapic_id)
+        .filter(|lapic_id| *lapic_id != bsp);
+
+    unsafe {
+        for (index, lapic_id) in [bsp].into_iter().chain(others).enumerate() {
+            CPUS.push(Cpu::new(index, lapic_id));
+        }
+
+        CPUS[0].load();
+
+        *ONLINE.lock() = 1;
+    }
+
+    debug::write(format_args!("[debug] found {} cpus\n", all().len()));
+}
+
+// sends every application processor to ap_main and waits for all of them to come up
+pub fn start(smp: &SmpResponse) {
+    for cpu in smp.cpus().iter().filter(|cpu| cpu.lapic_id != smp.bsp_lapic_id()) {
+        cpu.goto_address.write(ap_main);
+    }
+
+    while online() < all().len() {
+        hint::spin_loop();
+    }
+
+    debug::write(format_args!("[debug] {} cpus online\n", online()));
+}
+
+// where an application processor starts out, on a stack of its own from limine
+unsafe extern "C" fn ap_main(info: &smp::Cpu) -> ! {
+    vmm::init_ap();
+
+    let cpu = all().iter()
+        .find(|cpu| cpu.lapic_id == info.lapic_id)
+        .expect("started a cpu that limine did not report");
+
+    cpu.load();
+
+    gdt::init();
+
+    fpu::init();
+
+    interrupt::init_ap();
+
+    timer::init_ap();
+
+    debug::write(format_args!("[debug] cpu {} (lapic {}) is up\n", cpu.index, cpu.lapic_id));
+
+    *ONLINE.lock() += 1;
+
+    scheduler::idle();
+}
@@ -0,0 +1,348 @@
+use crate::interrupt::trap::TrapFrame;
+use crate::process::{self, thread::Tid, Pid, ProcessError, KILLED, USER_STACK_TOP};
+use crate::vmm::{AddressSpace, Region, RegionKind, VmmError, USER_END};
+use crate::{debug, scheduler};
+
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use core::mem;
+use core::ptr;
+
+
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    InvalidMagic,
+    Unsupported,
+    InvalidSegment,
+    // the entry point is not in the lower half, or not in an executable segment
+    InvalidEntry,
+    Process(ProcessError),
+    Vmm(VmmError),
+}
+
+impl From<ProcessError> for ElfError {
+    fn from(err: ProcessError) -> ElfError {
+        ElfError::Process(err)
+    }
+}
+
+impl From<VmmError> for ElfError {
+    fn from(err: VmmError) -> ElfError {
+        ElfError::Vmm(err)
+    }
+}
+
+#[non_exhaustive]
+pub struct Kind;
+
+impl Kind {
+    const EXEC: u16 = 2;
+}
+
+#[non_exhaustive]
+pub struct Segment;
+
+impl Segment {
+    const LOAD: u32 = 1;
+
+    const EXECUTE: u32 = 0x1;
+    const WRITE: u32 = 0x2;
+}
+
+#[non_exhaustive]
+pub struct Aux;
+
+impl Aux {
+    const NULL: u64 = 0;
+    const PHDR: u64 = 3;
+    const PHENT: u64 = 4;
+    const PHNUM: u64 = 5;
+    const PAGESZ: u64 = 6;
+    const ENTRY: u64 = 9;
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+
+        if self.flags & Segment::WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        if self.flags & Segment::EXECUTE == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
+}
+
+fn read<T: Copy>(data: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = offset.checked_add(mem::size_of::<T>() as u64).ok_or(ElfError::Truncated)?;
+
+    if end > data.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+
+    unsafe { Ok(ptr::read_unaligned(data.as_ptr().add(offset as usize) as *const T)) }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header: Header = read(data, 0)?;
+
+        if header.ident[..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err(ElfError::InvalidMagic);
+        }
+
+        // 64 bit, little endian, x86_64 executables are all we know how to run
+        if header.ident[4] != 2 || header.ident[5] != 1 || header.machine != 0x3e || header.kind != Kind::EXEC {
+            return Err(ElfError::Unsupported);
+        }
+
+        if header.phentsize as usize != mem::size_of::<ProgramHeader>() {
+            return Err(ElfError::Unsupported);
+        }
+
+        if header.entry == 0 || header.entry >= USER_END {
+            return Err(ElfError::InvalidEntry);
+        }
+
+        Ok(Elf {
+            data,
+            header,
+        })
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + '_ {
+        (0..self.header.phnum as u64).map(|index| {
+            read(self.data, self.header.phoff + index * self.header.phentsize as u64)
+        })
+    }
+
+    // maps every PT_LOAD segment into `address_space`, the part of a segment that is not backed by
+    // the file is left to demand paging which hands out zeroed pages. the entry point has to lie in
+    // one of the executable segments.
+    pub fn load(&self, address_space: &mut AddressSpace) -> Result<Option<u64>, ElfError> {
+        let mut phdr = None;
+        let mut entry_mapped = false;
+
+        for header in self.program_headers() {
+            let header = header?;
+
+            if header.kind != Segment::LOAD || header.memsz == 0 {
+                continue;
+            }
+
+            let end = header.vaddr.checked_add(header.memsz).ok_or(ElfError::InvalidSegment)?;
+            let file_end = header.offset.checked_add(header.filesz).ok_or(ElfError::InvalidSegment)?;
+
+            if header.filesz > header.memsz || end > USER_END || file_end > self.data.len() as u64 {
+                return Err(ElfError::InvalidSegment);
+            }
+
+            address_space.reserve(Region::new(
+                VirtAddr::new(header.vaddr),
+                header.memsz,
+                header.page_flags(),
+                RegionKind::Anonymous,
+            ))?;
+
+            address_space.write(VirtAddr::new(header.vaddr), &self.data[header.offset as usize..file_end as usize])?;
+
+            if header.flags & Segment::EXECUTE != 0 && (header.vaddr..end).contains(&self.header.entry) {
+                entry_mapped = true;
+            }
+
+            // the program headers are usually part of the first segment, the runtime finds them
+            // through the auxiliary vector.
+            if (header.offset..file_end).contains(&self.header.phoff) {
+                phdr = Some(header.vaddr + self.header.phoff - header.offset);
+            }
+        }
+
+        if !entry_mapped {
+            return Err(ElfError::InvalidEntry);
+        }
+
+        Ok(phdr)
+    }
+}
+
+// lays out argc, argv, envp and the auxiliary vector at the top of the user stack the way the
+// System V abi describes it and returns the initial stack pointer.
+fn setup_stack(address_space: &mut AddressSpace, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)]) -> Result<u64, ElfError> {
+    let mut top = USER_STACK_TOP;
+
+    let mut push_string = |string: &str| -> Result<u64, ElfError> {
+        top -= string.len() as u64 + 1;
+
+        address_space.write(VirtAddr::new(top), string.as_bytes())?;
+        address_space.write(VirtAddr::new(top + string.len() as u64), &[0])?;
+
+        Ok(top)
+    };
+
+    let argv = argv.iter().map(|arg| push_string(arg)).collect::<Result<Vec<u64>, ElfError>>()?;
+    let envp = envp.iter().map(|env| push_string(env)).collect::<Result<Vec<u64>, ElfError>>()?;
+
+    let mut words: Vec<u64> = Vec::new();
+
+    words.push(argv.len() as u64);
+    words.extend(argv.iter());
+    words.push(0);
+    words.extend(envp.iter());
+    words.push(0);
+
+    for (key, value) in auxv.iter().chain([(Aux::NULL, 0)].iter()) {
+        words.push(*key);
+        words.push(*value);
+    }
+
+    let bytes = words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>();
+
+    // rsp has to be 16 byte aligned at the entry point with argc sitting right at rsp, anything that
+    // does not fit below the stack limit fails to write with NoRegion.
+    let rsp = (top - bytes.len() as u64) & !0xf;
+
+    address_space.write(VirtAddr::new(rsp), &bytes)?;
+
+    Ok(rsp)
+}
+
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str], address_space: &mut AddressSpace) -> Result<TrapFrame, ElfError> {
+    let elf = Elf::parse(data)?;
+
+    let phdr = elf.load(address_space)?;
+
+    process::reserve_user_stack(address_space)?;
+
+    let mut auxv = Vec::from([
+        (Aux::PHENT, elf.header.phentsize as u64),
+        (Aux::PHNUM, elf.header.phnum as u64),
+        (Aux::PAGESZ, Size4KiB::SIZE),
+        (Aux::ENTRY, elf.entry()),
+    ]);
+
+    if let Some(phdr) = phdr {
+        auxv.push((Aux::PHDR, phdr));
+    }
+
+    let rsp = setup_stack(address_space, argv, envp, &auxv)?;
+
+    Ok(TrapFrame::user(elf.entry(), rsp))
+}
+
+pub fn spawn(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ElfError> {
+    let mut address_space = AddressSpace::new_process()?;
+
+    match load(data, argv, envp, &mut address_space) {
+        Ok(frame) => {
+            debug::write(format_args!("[debug] spawning elf at 0x{:x}\n", frame.rip));
+
+            let name = argv.first().copied().unwrap_or("elf");
+
+            Ok(process::spawn_with(name, Some(address_space), frame)?)
+        },
+        Err(err) => {
+            unsafe { address_space.destroy(); }
+
+            Err(err)
+        },
+    }
+}
+
+// replaces the image of the process that `tid` belongs to with `data`, `frame` is the trap frame
+// the thread returns to so it starts at the new entry point. the old image is only torn down once
+// the new one has loaded, a failed exec leaves the process untouched. every other thread of the
+// process goes away with the old image.
+pub fn exec(tid: Tid, data: &[u8], argv: &[&str], envp: &[&str], frame: &mut TrapFrame) -> Result<(), ElfError> {
+    let pid = process::map_thread(tid, |thread| thread.pid).ok_or(ProcessError::NoThread)?;
+
+    let mut address_space = AddressSpace::new_process()?;
+
+    let entry = match load(data, argv, envp, &mut address_space) {
+        Ok(entry) => entry,
+        Err(err) => {
+            unsafe { address_space.destroy(); }
+
+            return Err(err);
+        },
+    };
+
+    process::exit_threads(pid, Some(tid), KILLED);
+
+    // threads running on other cpus still use the old image until they notice they are killed
+    while process::get(pid, |proc| proc.threads.len() > 1).unwrap_or(false) {
+        scheduler::yield_now();
+    }
+
+    unsafe { address_space.activate(); }
+
+    process::map_thread(tid, |thread| {
+        if let Some(fpu) = &mut thread.fpu {
+            fpu.clear();
+            fpu.restore();
+        }
+    });
+
+    let old = process::map(pid, |proc| {
+        proc.name = argv.first().copied().unwrap_or("elf").to_string();
+
+        proc.address_space.replace(address_space)
+    }).flatten();
+
+    if let Some(old) = old {
+        unsafe { old.destroy(); }
+    }
+
+    *frame = entry;
+
+    Ok(())
+}
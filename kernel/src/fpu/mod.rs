@@ -0,0 +1,122 @@
+use crate::allocator::{self, frame};
+use crate::debug;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use x86_64::PhysAddr;
+
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr;
+
+// set from cpuid in init, the area is allocated to whatever size the enabled components need
+static mut AREA_SIZE: usize = 512;
+static mut XSAVE: bool = false;
+
+const FCW_DEFAULT: u16 = 0x37f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+
+// the x87, sse and avx registers of a process. the kernel is built without sse, so this state only
+// ever changes while a process runs and is switched eagerly along with the process.
+pub struct FpuState {
+    area: PhysAddr,
+}
+
+impl FpuState {
+    // a fresh area in the init state, xrstor treats the zeroed xsave header as every component
+    // being in its init state and fxrstor only needs the control words.
+    pub fn new() -> Option<FpuState> {
+        // xsave wants the area 64 byte aligned, frames are aligned well beyond that
+        let area = frame::alloc_contiguous(unsafe { AREA_SIZE } as u64)?;
+
+        let mut state = FpuState { area };
+
+        state.clear();
+
+        Some(state)
+    }
+
+    pub fn clear(&mut self) {
+        unsafe {
+            ptr::write_bytes(self.area(), 0, AREA_SIZE);
+
+            ptr::write_unaligned(self.area().add(FCW_OFFSET) as *mut u16, FCW_DEFAULT);
+            ptr::write_unaligned(self.area().add(MXCSR_OFFSET) as *mut u32, MXCSR_DEFAULT);
+        }
+    }
+
+    pub fn copy_from(&mut self, other: &FpuState) {
+        unsafe {
+            ptr::copy_nonoverlapping(other.area(), self.area(), AREA_SIZE);
+        }
+    }
+
+    fn area(&self) -> *mut u8 {
+        allocator::phys_to_virt(self.area).as_mut_ptr()
+    }
+
+    pub fn save(&mut self) {
+        unsafe {
+            if XSAVE {
+                asm!("xsave64 [{}]", in(reg) self.area(), in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) self.area(), options(nostack));
+            }
+        }
+    }
+
+    pub fn restore(&self) {
+        unsafe {
+            if XSAVE {
+                asm!("xrstor64 [{}]", in(reg) self.area(), in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) self.area(), options(nostack));
+            }
+        }
+    }
+
+    pub unsafe fn free(self) {
+        frame::dealloc_contiguous(self.area, AREA_SIZE as u64);
+    }
+}
+
+// turns on the fpu and sse for ring 3 and picks xsave over fxsave when the cpu supports it, every
+// state component we enable in xcr0 is saved across context switches.
+pub fn init() {
+    unsafe {
+        let features = __cpuid(1);
+
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+
+        if features.ecx & (1 << 26) != 0 {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+
+            let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+
+            if features.ecx & (1 << 28) != 0 {
+                xcr0 |= XCr0Flags::AVX;
+            }
+
+            XCr0::write(xcr0);
+
+            // ebx of leaf 0xd is the size of the area for the components enabled in xcr0
+            AREA_SIZE = __cpuid_count(0xd, 0).ebx as usize;
+            XSAVE = true;
+        }
+
+        asm!("fninit", options(nomem, nostack));
+
+        debug::write(format_args!("[debug] fpu: xsave: {}, area size: {}\n", XSAVE, AREA_SIZE));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::allocator::{self, frame};
+use crate::{cpu, debug};
+
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use alloc::boxed::Box;
+
+pub const DOUBLE_FAULT_IST: u16 = 0;
+pub const NMI_IST: u16 = 1;
+pub const MACHINE_CHECK_IST: u16 = 2;
+
+const STACK_SIZE: u64 = 0x5000;
+
+pub static mut SELECTORS: Selectors = Selectors::new();
+
+
+// the selectors are the same on every cpu since every gdt is laid out the same way, user data comes
+// before user code because that is the order sysret expects.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+impl Selectors {
+    pub const fn new() -> Selectors {
+        Selectors {
+            kernel_code: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+            kernel_data: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+            user_data: SegmentSelector::new(0, PrivilegeLevel::Ring3),
+            user_code: SegmentSelector::new(0, PrivilegeLevel::Ring3),
+            tss: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+        }
+    }
+}
+
+fn stack() -> VirtAddr {
+    let base = frame::alloc_contiguous(STACK_SIZE).expect("failed to allocate interrupt stack");
+
+    allocator::phys_to_virt(base) + STACK_SIZE
+}
+
+// the gdt and tss of a single cpu, both are leaked since a cpu never gives them up again
+pub struct Tables {
+    gdt: &'static GlobalDescriptorTable,
+    tss: *mut TaskStateSegment,
+}
+
+unsafe impl Send for Tables {}
+unsafe impl Sync for Tables {}
+
+impl Tables {
+    pub fn new() -> Tables {
+        let tss: &'static mut TaskStateSegment = Box::leak(Box::new(TaskStateSegment::new()));
+        let gdt: &'static mut GlobalDescriptorTable = Box::leak(Box::new(GlobalDescriptorTable::new()));
+
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST as usize] = stack();
+        tss.interrupt_stack_table[NMI_IST as usize] = stack();
+        tss.interrupt_stack_table[MACHINE_CHECK_IST as usize] = stack();
+        tss.privilege_stack_table[0] = stack();
+
+        let tss = tss as *mut TaskStateSegment;
+
+        unsafe {
+            SELECTORS = Selectors {
+                kernel_code: gdt.append(Descriptor::kernel_code_segment()),
+                kernel_data: gdt.append(Descriptor::kernel_data_segment()),
+                user_data: gdt.append(Descriptor::user_data_segment()),
+                user_code: gdt.append(Descriptor::user_code_segment()),
+                tss: gdt.append(Descriptor::tss_segment(&*tss)),
+            };
+        }
+
+        Tables {
+            gdt,
+            tss,
+        }
+    }
+
+    fn load(&self) {
+        unsafe {
+            self.gdt.load();
+
+            CS::set_reg(SELECTORS.kernel_code);
+            SS::set_reg(SELECTORS.kernel_data);
+            DS::set_reg(SELECTORS.kernel_data);
+            ES::set_reg(SELECTORS.kernel_data);
+
+            load_tss(SELECTORS.tss);
+        }
+    }
+}
+
+// the stack the calling cpu switches to when an interrupt arrives while running in ring 3
+pub fn set_kernel_stack(stack: VirtAddr) {
+    unsafe {
+        (*cpu::current().tables.tss).privilege_stack_table[0] = stack;
+    }
+}
+
+// loads the tables of the calling cpu, they are built along with the rest of its per cpu data
+pub fn init() {
+    cpu::current().tables.load();
+
+    debug::write(format_args!("[debug] loaded gdt: {:x?}\n", unsafe { SELECTORS }));
+}
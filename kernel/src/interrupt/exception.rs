@@ -0,0 +1,237 @@
+use super::trap::{handler, stub, TrapFrame};
+use crate::{KERNEL_TTY, debug, gdt, halt, process, scheduler};
+use crate::vmm::{VmmError, USER_END};
+use crate::apic::ipi;
+use crate::process::{stack, Pid, Signal};
+
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
+
+use core::fmt::{self, Write};
+
+
+const EXCEPTIONS: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point exception",
+    "alignment check",
+    "machine check",
+    "simd floating point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection exception",
+    "vmm communication exception",
+    "security exception",
+    "reserved",
+];
+
+#[non_exhaustive]
+pub struct Vector;
+
+impl Vector {
+    const DIVIDE_ERROR: u64 = 0;
+    const DEBUG: u64 = 1;
+    const NMI: u64 = 2;
+    const BREAKPOINT: u64 = 3;
+    const INVALID_OPCODE: u64 = 6;
+    const DOUBLE_FAULT: u64 = 8;
+    const SEGMENT_NOT_PRESENT: u64 = 11;
+    const STACK_SEGMENT_FAULT: u64 = 12;
+    const GENERAL_PROTECTION_FAULT: u64 = 13;
+    const PAGE_FAULT: u64 = 14;
+    const X87_FLOATING_POINT: u64 = 16;
+    const ALIGNMENT_CHECK: u64 = 17;
+    const MACHINE_CHECK: u64 = 18;
+    const SIMD_FLOATING_POINT: u64 = 19;
+}
+
+stub!(divide_error, 0);
+stub!(debug_exception, 1);
+stub!(non_maskable_interrupt, 2);
+stub!(breakpoint, 3);
+stub!(overflow, 4);
+stub!(bound_range_exceeded, 5);
+stub!(invalid_opcode, 6);
+stub!(device_not_available, 7);
+stub!(double_fault, 8, error_code);
+stub!(coprocessor_segment_overrun, 9);
+stub!(invalid_tss, 10, error_code);
+stub!(segment_not_present, 11, error_code);
+stub!(stack_segment_fault, 12, error_code);
+stub!(general_protection_fault, 13, error_code);
+stub!(page_fault, 14, error_code);
+stub!(x87_floating_point, 16);
+stub!(alignment_check, 17, error_code);
+stub!(machine_check, 18);
+stub!(simd_floating_point, 19);
+stub!(virtualization, 20);
+stub!(cp_protection_exception, 21, error_code);
+stub!(hv_injection_exception, 28);
+stub!(vmm_communication_exception, 29, error_code);
+stub!(security_exception, 30, error_code);
+
+// the remaining vectors below 32 are reserved and can not be raised by the cpu, the faults that can
+// happen while the stack itself is broken run on their own interrupt stacks.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(handler(divide_error));
+        idt.debug.set_handler_addr(handler(debug_exception));
+        idt.non_maskable_interrupt.set_handler_addr(handler(non_maskable_interrupt)).set_stack_index(gdt::NMI_IST);
+        idt.breakpoint.set_handler_addr(handler(breakpoint));
+        idt.overflow.set_handler_addr(handler(overflow));
+        idt.bound_range_exceeded.set_handler_addr(handler(bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(handler(invalid_opcode));
+        idt.device_not_available.set_handler_addr(handler(device_not_available));
+        idt.double_fault.set_handler_addr(handler(double_fault)).set_stack_index(gdt::DOUBLE_FAULT_IST);
+        idt[9].set_handler_addr(handler(coprocessor_segment_overrun));
+        idt.invalid_tss.set_handler_addr(handler(invalid_tss));
+        idt.segment_not_present.set_handler_addr(handler(segment_not_present));
+        idt.stack_segment_fault.set_handler_addr(handler(stack_segment_fault));
+        idt.general_protection_fault.set_handler_addr(handler(general_protection_fault));
+        idt.page_fault.set_handler_addr(handler(page_fault));
+        idt.x87_floating_point.set_handler_addr(handler(x87_floating_point));
+        idt.alignment_check.set_handler_addr(handler(alignment_check));
+        idt.machine_check.set_handler_addr(handler(machine_check)).set_stack_index(gdt::MACHINE_CHECK_IST);
+        idt.simd_floating_point.set_handler_addr(handler(simd_floating_point));
+        idt.virtualization.set_handler_addr(handler(virtualization));
+        idt.cp_protection_exception.set_handler_addr(handler(cp_protection_exception));
+        idt.hv_injection_exception.set_handler_addr(handler(hv_injection_exception));
+        idt.vmm_communication_exception.set_handler_addr(handler(vmm_communication_exception));
+        idt.security_exception.set_handler_addr(handler(security_exception));
+    }
+}
+
+fn name(frame: &TrapFrame) -> &'static str {
+    EXCEPTIONS[frame.vector as usize % EXCEPTIONS.len()]
+}
+
+// `write` is debug::write_raw on the paths that must not take a lock
+fn report(frame: &TrapFrame, write: fn(fmt::Arguments)) {
+    write(format_args!("[debug] {} (vector {}), error_code: 0x{:x}\n{}", name(frame), frame.vector, frame.error_code, frame));
+
+    if frame.vector == Vector::PAGE_FAULT {
+        write(format_args!("[debug] cr2: 0x{:x}\n", Cr2::read_raw()));
+    }
+}
+
+// puts the exception on the screen too, which wipes whatever was there. only for exceptions that
+// end something, the screen is left alone if the fault hit while it was being drawn.
+fn show(frame: &TrapFrame) {
+    let name = name(frame);
+
+    if let Some(tty) = unsafe { KERNEL_TTY.try_lock() }.as_mut().and_then(|tty| tty.as_mut()) {
+        tty.clear();
+
+        if write!(tty, "{} (vector {}), error_code: 0x{:x}\n{}", name, frame.vector, frame.error_code, frame).is_err() {
+            tty.write("exception: failed to format");
+        }
+
+        tty.render();
+    }
+}
+
+// the signal a process that caused the exception gets killed with
+fn signal(frame: &TrapFrame) -> i64 {
+    match frame.vector {
+        Vector::DIVIDE_ERROR | Vector::X87_FLOATING_POINT | Vector::SIMD_FLOATING_POINT => Signal::FPE,
+        Vector::INVALID_OPCODE => Signal::ILL,
+        Vector::SEGMENT_NOT_PRESENT | Vector::STACK_SEGMENT_FAULT | Vector::GENERAL_PROTECTION_FAULT
+            | Vector::PAGE_FAULT | Vector::ALIGNMENT_CHECK => Signal::SEGV,
+        _ => Signal::KILL,
+    }
+}
+
+// faults in the lower half are resolved against the address space of the running process, the upper
+// half belongs to the kernel and is never mapped lazily.
+fn resolve_page_fault(pid: Pid, frame: &TrapFrame) -> Result<(), VmmError> {
+    let addr = VirtAddr::new_truncate(Cr2::read_raw());
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+    if addr.as_u64() >= USER_END {
+        return Err(VmmError::Protection);
+    }
+
+    process::map(pid, |proc| {
+        proc.address_space.as_mut()
+            .ok_or(VmmError::NoRegion)
+            .and_then(|address_space| address_space.handle_fault(addr, error_code))
+    }).unwrap_or(Err(VmmError::NoRegion))
+}
+
+pub extern "C" fn handle(frame: &mut TrapFrame) {
+    // the fatal ones come first and must not touch any lock, the fault may have hit while one of
+    // them was held.
+    match frame.vector {
+        // an nmi can hit the kernel between the swapgs and the iretq on the way back to ring 3, so
+        // gs may belong to the user here and nothing that goes through it can be used.
+        Vector::NMI => {
+            if !ipi::handle_nmi() {
+                report(frame, debug::write_raw);
+            }
+
+            return;
+        },
+        Vector::DOUBLE_FAULT | Vector::MACHINE_CHECK => {
+            // a page fault that can not even push its frame is most likely a kernel stack overflow
+            if frame.vector == Vector::DOUBLE_FAULT && stack::is_guard(VirtAddr::new_truncate(Cr2::read_raw())) {
+                debug::write_raw(format_args!("[debug] kernel stack overflow at 0x{:x}\n", Cr2::read_raw()));
+            }
+
+            report(frame, debug::write_raw);
+            show(frame);
+
+            halt();
+        },
+        Vector::DEBUG | Vector::BREAKPOINT => {
+            report(frame, debug::write);
+
+            return;
+        },
+        Vector::PAGE_FAULT => {
+            if let Some(pid) = scheduler::current() {
+                match resolve_page_fault(pid, frame) {
+                    Ok(()) => return,
+                    Err(err) => debug::write(format_args!("[debug] unresolved page fault in process {}: {:?}\n", pid, err)),
+                }
+            }
+        },
+        _ => {},
+    }
+
+    report(frame, debug::write);
+    show(frame);
+
+    // a fault in ring 3 only takes down the process that caused it, a fault in the kernel means the
+    // kernel itself is broken and may well hold locks, even if it happened on behalf of a process.
+    match scheduler::current().filter(|_| frame.is_user()) {
+        Some(pid) => {
+            let name = process::get(pid, |proc| proc.name.clone()).unwrap_or_default();
+
+            debug::write(format_args!("[debug] killing process {} ({})\n", pid, name));
+
+            scheduler::exit(-signal(frame));
+        },
+        None => halt(),
+    }
+}
@@ -1,16 +1,19 @@
-use crate::{KERNEL_TTY, process, debug, halt, scheduler, syscall::Syscall, scancodes::Scancodes, process::Context};
+pub mod trap;
+pub mod exception;
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use trap::{handler, stub, TrapFrame};
+use crate::acpi::{madt, AcpiError};
+use crate::apic::{self, ioapic, ipi, ApicError};
+use crate::{cpu, debug, keyboard, scheduler, timer, syscall::Syscall, scancodes::Scancodes};
+use crate::vfs::ata;
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::PrivilegeLevel;
 use x86_64::instructions::port::Port;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
 
-use core::fmt::Write;
-use core::arch::asm;
-
-// TODO: disable interrupts while handling other interrupts
-
 
 static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(32, 40) });
 static SCANCODES: Mutex<Scancodes> = Mutex::new(Scancodes::new());
@@ -19,11 +22,18 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        idt.breakpoint.set_handler_fn(breakpoint);
-        idt.double_fault.set_handler_fn(double_fault);
-        idt[32].set_handler_fn(timer_interrupt);
-        idt[33].set_handler_fn(keyboard_interrupt);
-        idt[128].set_handler_fn(syscall_interrupt);
+        exception::install(&mut idt);
+
+        unsafe {
+            idt[32].set_handler_addr(handler(timer));
+            idt[33].set_handler_addr(handler(keyboard));
+            idt[36].set_handler_addr(handler(serial));
+            idt[46].set_handler_addr(handler(ata));
+            idt[128].set_handler_addr(handler(syscall)).set_privilege_level(PrivilegeLevel::Ring3);
+            idt[240].set_handler_addr(handler(reschedule));
+            idt[241].set_handler_addr(handler(call_function));
+            idt[255].set_handler_addr(handler(spurious));
+        }
 
         return idt;
     };
@@ -33,9 +43,24 @@ pub fn init() {
     IDT.load();
 
     unsafe {
+        // remapped even when it ends up disabled, a spurious interrupt from it must not look like
+        // an exception.
         PICS.lock().initialize();
+    }
 
-        PICS.lock().write_masks(0, 0);
+    match init_apic() {
+        // machines without an 8259 leave it out of the madt, there is nothing to mask then
+        Ok(true) => unsafe { PICS.lock().disable() },
+        Ok(false) => {},
+        Err(err) => {
+            match err {
+                ApicError::Acpi(AcpiError::NotFound) => debug::write(format_args!("[debug] no madt, staying on the 8259\n")),
+                ApicError::Vmm(err) => debug::write(format_args!("[debug] failed to map the apic registers, falling back to the 8259: {:?}\n", err)),
+                err => debug::write(format_args!("[debug] no usable apic, falling back to the 8259: {:?}\n", err)),
+            }
+
+            unsafe { PICS.lock().write_masks(0, 0) }
+        },
     }
 
     x86_64::instructions::interrupts::enable();
@@ -43,231 +68,167 @@ pub fn init() {
     debug::write(format_args!("[debug] initialized\n"));
 }
 
-#[no_mangle]
-extern "x86-interrupt" fn syscall_interrupt(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        x86_64::instructions::interrupts::disable();
+// hands the legacy irqs to the io apics, all of them go to the boot processor for now. returns
+// whether there is an 8259 that has to be masked.
+fn init_apic() -> Result<bool, ApicError> {
+    let madt = madt::parse()?;
 
-        let mut syscall = Syscall::new();
+    apic::init(&madt)?;
 
-        asm!(
-            "mov {rax}, [rsp + 112]",
-            "mov {rdi}, [rsp + 112 + 32]",
-            "mov {rsi}, [rsp + 112 + 24]",
-            "mov {rdx}, [rsp + 112 + 16]",
-            rax = out(reg) syscall.args[0],
-            rdi = out(reg) syscall.args[1],
-            rsi = out(reg) syscall.args[2],
-            rdx = out(reg) syscall.args[3],
-        );
+    let lapic_id = cpu::current().lapic_id;
+
+    for irq in [Irq::TIMER, Irq::KEYBOARD, Irq::COM1, Irq::ATA] {
+        match ioapic::route(irq, Irq::vector(irq), lapic_id) {
+            Ok(()) => {},
+            Err(ApicError::NoIoApic(gsi)) => debug::write(format_args!("[debug] irq {} arrives at gsi {}, which no io apic handles\n", irq, gsi)),
+            Err(err) => debug::write(format_args!("[debug] failed to route irq {}: {:?}\n", irq, err)),
+        }
+    }
+
+    ipi::init_local();
+
+    Ok(madt.legacy_pics)
+}
 
-        syscall.perform();
+// the idt is shared by every cpu, only the boot processor talks to the pic and io apics
+pub fn init_ap() {
+    IDT.load();
 
-        PICS.lock().notify_end_of_interrupt(128);
+    if apic::is_enabled() {
+        apic::init_local();
 
-        x86_64::instructions::interrupts::enable();
+        ipi::init_local();
     }
+
+    x86_64::instructions::interrupts::enable();
 }
 
-#[no_mangle]
-extern "x86-interrupt" fn timer_interrupt(stack_frame: InterruptStackFrame) {
-    unsafe {
-        x86_64::instructions::interrupts::disable();
-
-        // TODO: all the registers are already pushed on the stack for us before the rust compiler
-        // has had any time to mess with them, we need to figure a way to read these registers
-        //
-        // sp = rsp + 0xb0
-        // rax = [sp]
-        // rcx = [sp + 8]
-        // rdx = [sp + 16]
-        // rsi = [sp + 24]
-        // rdi = [sp + 32]
-        // r8  = [sp + 40]
-        // r9  = [sp + 48]
-        // r10 = [sp + 56]
-        // r11 = [sp + 64]
-
-        if process::READY {
-            let mut context = Context::new();
-
-            // TODO: some registers still seem to be saved wrong
-
-            // 272
-            asm!(
-                "mov {rdi}, [rsp + 512 + 32]",
-                "mov {rsi}, [rsp + 512 + 24]",
-                "mov {rdx}, [rsp + 512 + 16]",
-                "mov {rcx}, [rsp + 512 + 8]",
-                "mov {rax}, [rsp + 512]",
-                "mov {rbp}, rbp",
-                "mov {rbx}, rbx",
-                rdi = out(reg) context.rdi,
-                rsi = out(reg) context.rsi,
-                rdx = out(reg) context.rdx,
-                rcx = out(reg) context.rcx,
-                rax = out(reg) context.rax,
-                rbp = out(reg) context.rbp,
-                rbx = out(reg) context.rbx,
-            );
-
-            asm!(
-                "mov {r8}, [rsp + 512 + 40]",
-                "mov {r9}, [rsp + 512 + 48]",
-                "mov {r10}, [rsp + 512 + 56]",
-                "mov {r11}, [rsp + 512 + 64]",
-                r8 = out(reg) context.r8,
-                r9 = out(reg) context.r9,
-                r10 = out(reg) context.r10,
-                r11 = out(reg) context.r11,
-            );
-
-            /*
-            asm!(
-                "mov {rsp}, {sp}",
-                "mov {rip}, {ip}",
-                rsp = out(reg) context.rsp,
-                rip = out(reg) context.rip,
-                sp = in(reg) stack_frame.stack_pointer.as_u64(),
-                ip = in(reg) stack_frame.instruction_pointer.as_u64(),
-            );
-            */
-
-            context.rsp = stack_frame.stack_pointer.as_u64() as i64;
-            context.rip = stack_frame.instruction_pointer.as_u64() as i64;
-
-            scheduler::schedule(context);
-
-            /*
-            asm!(
-                // call convention
-                // rdi, rsi, rdx, rcx, r8, r9, [rsp], [rsp + 8], [rsp + 16]
-
-//                            rdi       rsi       rdx       rcx       r8        r9        rsp       rsp + 8   rsp + 16  sp + 24  rsp + 32 rsp + 40  rsp + 48
-// pub extern "C" fn schedule(rdi: i64, rsi: i64, rdx: i64, rcx: i64, rbp: i64, rsp: i64, rbx: i64, rax: i64, rip: i64, r8: i64, r9: i64, r10: i64, r11: i64) {
-                // "mov [rsp - 8], {ip}",
-                "mov [rsp - 40], {ip}",
-
-                "mov rdi, [rsp + 176 + 40]",
-                "mov rsi, [rsp + 176 + 48]",
-                "mov rdx, [rsp + 176 + 56]",
-                "mov rcx, [rsp + 176 + 64]",
-
-                "mov [rsp - 32], rdi",
-                "mov [rsp - 24], rsi",
-                "mov [rsp - 16], rdx",
-                "mov [rsp - 8], rcx",
-
-                "mov rdi, [rsp + 176 + 32]",
-                "mov rsi, [rsp + 176 + 24]",
-                "mov rdx, [rsp + 176 + 16]",
-                "mov rcx, [rsp + 176 + 8]",
-                "mov r8, rbp",
-                "mov r9, {sp}",
-
-                "mov rax, [rsp + 176]",
-
-                // "sub rsp, 24",
-                "sub rsp, 56",
-
-                "mov [rsp + 8], rax",
-
-                "mov [rsp], rbx",
-
-                // we may need this for debugging later
-                // "mov [rsp + 16], {ip}",
-
-                "call schedule",
-
-                // "add rsp, 24",
-                "add rsp, 56",
-
-                sp = in(reg) stack_frame.stack_pointer.as_u64(),
-                ip = in(reg) stack_frame.instruction_pointer.as_u64(),
-            );
-            */
-
-            PICS.lock().notify_end_of_interrupt(32);
-
-            asm!(
-                // remove registers from stack
-                // "add rsp, 0xb0",
-
-                // jump to next_process
-                // "jmp next_process"
-
-                // MonogonOS
-                // TODO: maybe we need to use iret in order to call the function? that will maybe
-                // preserve the stack pointer and stuff
-                //
-                // we want to make iretq return to the next_process function.
-                // TODO: MAYBE WE NEED TO POP?, OR MAYBE THE STRUCT IS CORRUPTED IN ANY OTHER WAY?
-                "push {stack_segment:r}",
-                "push {new_stack_pointer}",
-                "push {rflags}",
-                "push {code_segment:r}",
-                "push {instruction_pointer}",
-                "iretq",
-                rflags = in(reg) stack_frame.cpu_flags.bits(),
-                // for some reason the stack pointer is always decremented by 8 bytes so we need to
-                // increment it by 8 just to stop it from overflowing
-                // new_stack_pointer = in(reg) stack_frame.stack_pointer.as_u64(),
-                // TODO: this also crashes because rsp is set to 0 when we first start
-                new_stack_pointer = in(reg) scheduler::NEXT_PROCESS.context.rsp,
-                code_segment = in(reg) stack_frame.code_segment.0,
-                stack_segment = in(reg) stack_frame.stack_segment.0,
-                instruction_pointer = in(reg) scheduler::next_process as u64,
-            );
-        } else {
-            PICS.lock().notify_end_of_interrupt(32);
-
-            x86_64::instructions::interrupts::enable();
-        }
+// the isa irqs we handle, they keep the vectors the remapped 8259 would give them
+#[non_exhaustive]
+pub struct Irq;
+
+impl Irq {
+    pub const TIMER: u8 = 0;
+    pub const KEYBOARD: u8 = 1;
+    pub const COM1: u8 = 4;
+    pub const ATA: u8 = 14;
+
+    pub const fn vector(irq: u8) -> u8 {
+        32 + irq
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+#[non_exhaustive]
+pub struct Vector;
+
+impl Vector {
+    pub const TIMER: u64 = 32;
+    const KEYBOARD: u64 = 33;
+    const COM1: u64 = 36;
+    const ATA: u64 = 46;
+    const SYSCALL: u64 = 128;
+    pub const RESCHEDULE: u64 = 240;
+    pub const CALL_FUNCTION: u64 = 241;
+    const SPURIOUS: u64 = apic::SPURIOUS_VECTOR as u64;
+}
 
-    if let Some(character) = SCANCODES.lock().advance(scancode) {
-        debug::write(format_args!("character: {:?}\n", character));
+stub!(timer, 32);
+stub!(keyboard, 33);
+stub!(serial, 36);
+stub!(ata, 46);
+stub!(syscall, 128);
+stub!(reschedule, 240);
+stub!(call_function, 241);
+stub!(spurious, 255);
+
+// acknowledges `vector` with whichever controller delivered it
+fn end_of_interrupt(vector: u64) {
+    match apic::is_enabled() {
+        true => apic::eoi(),
+        false => unsafe { PICS.lock().notify_end_of_interrupt(vector as u8) },
     }
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(33);
+// every stub ends up here with the full state of whatever got interrupted on the stack, anything
+// written to the frame is what the interrupted code resumes with.
+pub extern "C" fn dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        0..=31 => exception::handle(frame),
+        Vector::TIMER => timer_interrupt(frame),
+        Vector::KEYBOARD => keyboard_interrupt(frame),
+        Vector::COM1 => serial_interrupt(frame),
+        Vector::ATA => ata_interrupt(frame),
+        // the apic does not expect an eoi for these
+        Vector::SPURIOUS => {},
+        Vector::SYSCALL => syscall_interrupt(frame),
+        Vector::RESCHEDULE => reschedule_interrupt(frame),
+        Vector::CALL_FUNCTION => call_function_interrupt(frame),
+        _ => debug::write(format_args!("[debug] unexpected interrupt: {}\n", frame.vector)),
     }
 }
 
-#[no_mangle]
-extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-    debug::write(format_args!("[debug] double fault: {:#?}\nerror_code: {}\n", stack_frame, error_code));
+fn syscall_interrupt(frame: &mut TrapFrame) {
+    let mut syscall = Syscall::new();
 
-    if let Some(tty) = unsafe { KERNEL_TTY.lock().as_mut() } {
-        tty.clear();
+    syscall.args = [frame.rax as i64, frame.rdi as i64, frame.rsi as i64, frame.rdx as i64];
 
-        if write!(tty, "unrecovarable double fault: {:#?}\nerror_code: {}", stack_frame, error_code).is_err() {
-            tty.write("unrecovarable double fault: failed to format");
-        }
+    frame.rax = match syscall.perform(frame) {
+        Ok(value) => value as u64,
+        Err(err) => err.code() as u64,
+    };
+}
 
-        tty.render();
-    }
+fn timer_interrupt(_frame: &mut TrapFrame) {
+    // the interrupt has to be acknowledged before switching, the task we switch to may not come
+    // back through here for a long time.
+    end_of_interrupt(Vector::TIMER);
+
+    timer::tick();
 
-    halt();
+    scheduler::tick();
 }
 
-extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-    debug::write(format_args!("[debug] breakpoint\n"));
+// another cpu queued a thread for us, we may have to give up the current one for it
+fn reschedule_interrupt(_frame: &mut TrapFrame) {
+    end_of_interrupt(Vector::RESCHEDULE);
 
-    if let Some(tty) = unsafe { KERNEL_TTY.lock().as_mut() } {
-        tty.clear();
+    scheduler::reschedule();
+}
 
-        if write!(tty, "kernel interrupt: {:#?}", stack_frame).is_err() {
-            tty.write("kernel interrupt: failed to format");
-        }
+fn call_function_interrupt(_frame: &mut TrapFrame) {
+    end_of_interrupt(Vector::CALL_FUNCTION);
+
+    ipi::handle_calls();
+}
 
-        tty.render();
+fn keyboard_interrupt(_frame: &mut TrapFrame) {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    if let Some(character) = SCANCODES.lock().advance(scancode) {
+        keyboard::push(character);
     }
+
+    end_of_interrupt(Vector::KEYBOARD);
 }
 
+// nothing reads the serial port through interrupts yet, reading the identification register and
+// the pending byte is enough to make the uart drop the line.
+fn serial_interrupt(_frame: &mut TrapFrame) {
+    unsafe {
+        Port::<u8>::new(0x3fa).read();
 
+        if Port::<u8>::new(0x3fd).read() & 1 != 0 {
+            Port::<u8>::new(0x3f8).read();
+        }
+    }
+
+    end_of_interrupt(Vector::COM1);
+}
+
+// readers of the ata drive sleep until the drive raises this, once per sector
+fn ata_interrupt(_frame: &mut TrapFrame) {
+    ata::interrupt();
+
+    end_of_interrupt(Vector::ATA);
+}
@@ -0,0 +1,217 @@
+use crate::gdt;
+
+use x86_64::VirtAddr;
+
+use core::arch::asm;
+use core::fmt;
+
+const RFLAGS_INTERRUPTS: u64 = 0x202;
+
+
+// the layout of everything an entry stub leaves on the stack, starting with the last register pushed
+// and ending with the frame pushed by the cpu itself.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub const fn new() -> TrapFrame {
+        TrapFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vector: 0,
+            error_code: 0,
+            rip: 0,
+            cs: 0,
+            rflags: 0,
+            rsp: 0,
+            ss: 0,
+        }
+    }
+
+    pub fn kernel(rip: u64, rsp: u64) -> TrapFrame {
+        unsafe {
+            TrapFrame {
+                rip,
+                rsp,
+                cs: gdt::SELECTORS.kernel_code.0 as u64,
+                ss: gdt::SELECTORS.kernel_data.0 as u64,
+                rflags: RFLAGS_INTERRUPTS,
+                ..TrapFrame::new()
+            }
+        }
+    }
+
+    pub fn user(rip: u64, rsp: u64) -> TrapFrame {
+        unsafe {
+            TrapFrame {
+                rip,
+                rsp,
+                cs: gdt::SELECTORS.user_code.0 as u64,
+                ss: gdt::SELECTORS.user_data.0 as u64,
+                rflags: RFLAGS_INTERRUPTS,
+                ..TrapFrame::new()
+            }
+        }
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.cs & 0x3 == 0x3
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rax={:016x} rbx={:016x} rcx={:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "rdx={:016x} rsi={:016x} rdi={:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "rbp={:016x} rsp={:016x} r8 ={:016x}", self.rbp, self.rsp, self.r8)?;
+        writeln!(f, "r9 ={:016x} r10={:016x} r11={:016x}", self.r9, self.r10, self.r11)?;
+        writeln!(f, "r12={:016x} r13={:016x} r14={:016x}", self.r12, self.r13, self.r14)?;
+        writeln!(f, "r15={:016x} rip={:016x} rfl={:016x}", self.r15, self.rip, self.rflags)?;
+        writeln!(f, "cs ={:016x} ss ={:016x}", self.cs, self.ss)
+    }
+}
+
+// where the idt has to send the cpu for a stub
+pub fn handler(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+// every stub pushes a vector number, and a zero in place of the error code for vectors where the
+// cpu does not push one, so that all of them end up with the same TrapFrame on the stack.
+macro_rules! stub {
+    ($name:ident, $vector:literal) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!(
+                "push 0",
+                concat!("push ", $vector),
+                "jmp {entry}",
+                entry = sym $crate::interrupt::trap::trap_entry,
+                options(noreturn),
+            );
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!(
+                concat!("push ", $vector),
+                "jmp {entry}",
+                entry = sym $crate::interrupt::trap::trap_entry,
+                options(noreturn),
+            );
+        }
+    };
+}
+
+pub(crate) use stub;
+
+#[naked]
+pub unsafe extern "C" fn trap_entry() {
+    asm!(
+        // coming from ring 3 means gs still belongs to the user, swap in the per cpu data of the
+        // kernel. the cs pushed by the cpu sits above the vector and the error code.
+        "test byte ptr [rsp + 24], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+
+        // the cpu aligns the stack before pushing its frame and we pushed an even number of
+        // quadwords on top of that, so the stack is already aligned for the call.
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+
+        "jmp {trap_return}",
+        handler = sym super::dispatch,
+        trap_return = sym trap_return,
+        options(noreturn),
+    );
+}
+
+// restores a TrapFrame sitting at rsp, this is also where a new task starts out since its kernel
+// stack is prepared to look like it was interrupted right before its first instruction.
+#[naked]
+pub unsafe extern "C" fn trap_return() {
+    asm!(
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+
+        // drop the vector and error code
+        "add rsp, 16",
+
+        "test byte ptr [rsp + 8], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+
+        "iretq",
+        options(noreturn),
+    );
+}
@@ -0,0 +1,53 @@
+use crate::sync::semaphore::Semaphore;
+
+use x86_64::instructions::interrupts;
+
+use alloc::collections::VecDeque;
+
+
+// whatever is typed while nobody reads is kept up to this many bytes, the rest is dropped
+const INPUT_SIZE: usize = 4096;
+
+static INPUT: spin::Mutex<VecDeque<u8>> = spin::Mutex::new(VecDeque::new());
+
+// counts the bytes in INPUT, so readers can block until there is something to read
+static AVAILABLE: Semaphore = Semaphore::new(0);
+
+// called from the keyboard interrupt with every decoded character
+pub fn push(character: char) {
+    let mut bytes = [0; 4];
+
+    for byte in character.encode_utf8(&mut bytes).bytes() {
+        let pushed = interrupts::without_interrupts(|| {
+            let mut input = INPUT.lock();
+
+            (input.len() < INPUT_SIZE).then(|| input.push_back(byte)).is_some()
+        });
+
+        if pushed {
+            AVAILABLE.signal();
+        }
+    }
+}
+
+// blocks until at least one byte was typed, then takes as much of the input as fits into `buffer`
+pub fn read(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+
+    AVAILABLE.wait();
+
+    let mut count = 0;
+
+    loop {
+        if let Some(byte) = interrupts::without_interrupts(|| INPUT.lock().pop_front()) {
+            buffer[count] = byte;
+            count += 1;
+        }
+
+        if count == buffer.len() || !AVAILABLE.try_wait() {
+            return count;
+        }
+    }
+}
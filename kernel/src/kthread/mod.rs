@@ -0,0 +1,91 @@
+use crate::interrupt::trap::TrapFrame;
+use crate::process::{self, Pid};
+use crate::scheduler;
+use crate::sync::{condvar::Condvar, mutex::Mutex};
+use crate::vmm::VmmError;
+
+use x86_64::instructions::interrupts;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+type Main = Box<dyn FnOnce() + Send>;
+
+
+// where a kernel thread leaves its result, joining threads sleep on `done` until it is there
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    done: Condvar,
+}
+
+pub struct JoinHandle<T> {
+    pid: Pid,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    // blocks until the thread returned and hands back its result
+    pub fn join(self) -> T {
+        let result = self.packet.result.lock();
+
+        self.packet.done.wait_while(result, |result| result.is_none())
+            .take()
+            .expect("kernel thread exited without a result")
+    }
+}
+
+// every kernel thread starts here with the closure it was spawned with in rdi
+extern "C" fn entry(main: *mut Main) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+
+    main();
+
+    exit(0);
+}
+
+// ends the calling kernel thread
+pub fn exit(code: i64) -> ! {
+    interrupts::disable();
+
+    scheduler::exit(code);
+}
+
+// runs `f` as a preemptible kernel thread on its own kernel stack, sharing the kernel address space
+pub fn spawn<F, T>(name: &str, f: F) -> Result<JoinHandle<T>, VmmError> where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+
+    let slot = packet.clone();
+
+    let main: Main = Box::new(move || {
+        let value = f();
+
+        *slot.result.lock() = Some(value);
+
+        // join takes the handle by value, there is never more than one thread waiting
+        slot.done.notify_one();
+    });
+
+    let main = Box::into_raw(Box::new(main));
+
+    let frame = TrapFrame {
+        rdi: main as u64,
+        ..TrapFrame::kernel(entry as usize as u64, 0)
+    };
+
+    match process::spawn_with(name, None, frame) {
+        Ok(pid) => Ok(JoinHandle { pid, packet }),
+        Err(err) => {
+            // the thread never ran, so the closure is still ours to drop
+            drop(unsafe { Box::from_raw(main) });
+
+            Err(err)
+        },
+    }
+}
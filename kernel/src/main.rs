@@ -8,24 +8,37 @@
 #![feature(ptr_as_ref_unchecked)]
 #![feature(const_refs_to_cell)]
 #![feature(slice_internals)]
+#![feature(naked_functions)]
+#![feature(asm_const)]
 #![test_runner(_test)]
 
 extern crate alloc;
 
+mod acpi;
 mod allocator;
+mod apic;
+mod cpu;
+mod elf;
+mod fpu;
+mod gdt;
 mod scheduler;
 mod process;
 mod debug;
 mod interrupt;
+mod keyboard;
+mod kthread;
 mod scancodes;
 mod tty;
 mod vfs;
+mod sync;
 mod syscall;
+mod timer;
+mod vmm;
 
-use vfs::ata::Ata;
+use vfs::ata::{AtaError, ATA};
 use tty::TTY;
 
-use limine::request::{FramebufferRequest, HhdmRequest, MemoryMapRequest, StackSizeRequest};
+use limine::request::{FramebufferRequest, HhdmRequest, KernelAddressRequest, KernelFileRequest, MemoryMapRequest, ModuleRequest, RsdpRequest, SmpRequest, StackSizeRequest};
 use limine::BaseRevision;
 use spin::Mutex;
 
@@ -35,7 +48,6 @@ use core::fmt::Write;
 use core::panic::PanicInfo;
 
 static mut KERNEL_TTY: Mutex<Option<TTY>> = Mutex::new(None);
-static mut KERNEL_TICKS: Mutex<usize> = Mutex::new(0);
 
 #[used]
 static BASE_REVISION: BaseRevision = BaseRevision::new();
@@ -49,6 +61,21 @@ static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 #[used]
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
+#[used]
+static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
+
+#[used]
+static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+
+#[used]
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+
+#[used]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
+#[used]
+static SMP_REQUEST: SmpRequest = SmpRequest::new();
+
 #[used]
 static STACK_SIZE: StackSizeRequest = StackSizeRequest::new().with_size(0x32000);
 
@@ -117,8 +144,44 @@ unsafe extern "C" fn _start() -> ! {
 
     debug::write(format_args!("[debug] starting\n"));
 
+    let hhdm = HHDM_REQUEST.get_response().expect("failed to get hhdm");
+    let memory_map = MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("failed to get memory map");
+
+    let kernel_address = KERNEL_ADDRESS_REQUEST
+        .get_response()
+        .expect("failed to get kernel address");
+
+    allocator::init(&memory_map, hhdm);
+
+    vmm::init(&memory_map, kernel_address);
+
+    // the interrupt controllers are described by acpi, without it we stay on the 8259
+    if let Err(err) = acpi::init(RSDP_REQUEST.get_response()) {
+        debug::write(format_args!("[debug] failed to initialize acpi: {:?}\n", err));
+    }
+
+    // everything from here on may want to know which cpu it runs on
+    let smp = SMP_REQUEST.get_response();
+
+    cpu::init(smp);
+
+    // the idt picks up the code segment that is loaded while it is built, so our own gdt has to be
+    // in place before the interrupts are initialized.
+    gdt::init();
+
+    fpu::init();
+
     interrupt::init();
 
+    if let Some(response) = KERNEL_FILE_REQUEST.get_response() {
+        configure(response.file().cmdline());
+    }
+
+    // the lapic timer gets calibrated against the pit, so the apic has to be set up first
+    timer::init();
+
     if let Some(response) = FRAMEBUFFER.get_response() {
         if let Some(framebuffer) = response.framebuffers().next() {
             let mut lock = KERNEL_TTY.lock();
@@ -133,13 +196,6 @@ unsafe extern "C" fn _start() -> ! {
         tty.render();
     }
 
-    let hhdm = HHDM_REQUEST.get_response().expect("failed to get hhdm");
-    let memory_map = MEMORY_MAP_REQUEST
-        .get_response()
-        .expect("failed to get memory map");
-
-    allocator::init(&memory_map, hhdm);
-
     let addr = allocator::ALLOC.alloc(Layout::new::<[u64; 20]>().align_to(128).unwrap());
     debug::write(format_args!("[debug] allocated [u64; 20]: {:x?}\n", addr));
 
@@ -152,45 +208,112 @@ unsafe extern "C" fn _start() -> ! {
     allocator::ALLOC.dealloc(addr2, Layout::new::<[u64; 12]>().align_to(128).unwrap());
     debug::write(format_args!("[debug] deallocated: {:x?}\n", addr2));
 
-    /*
-    if let Err(err) = vfs::init() {
-        panic!("vfs failed to initalize: {:?}", err);
+    // limine.cfg tags the initrd module with the `initrd` cmdline, its memory is part of the hhdm
+    let initrd = MODULE_REQUEST.get_response()
+        .and_then(|response| response.modules().iter().find(|module| module.cmdline() == b"initrd"))
+        .map(|module| core::slice::from_raw_parts(module.addr() as *const u8, module.size() as usize));
+
+    if initrd.is_none() {
+        debug::write(format_args!("[debug] no initrd was loaded\n"));
     }
-    */
 
-    let mut ata = Ata::new();
+    if let Err(err) = vfs::init(initrd) {
+        panic!("vfs failed to initalize: {:?}", err);
+    }
 
-    debug::write(format_args!("[debug] ata::new() done!\n"));
+    if let Err(err) = kthread::spawn("kinit", kinit) {
+        debug::write(format_args!("[debug] failed to spawn kinit: {:?}\n", err));
+    }
 
-    if let Err(err) = ata.identify() {
-        debug::write(format_args!("[debug] failed to identify ata drive: {:?}\n", err));
+    if let Some(smp) = smp {
+        cpu::start(smp);
     }
 
-    debug::write(format_args!("[debug] identify done!\n"));
+    // every cpu sits in its idle task by now, which is what the scheduler falls back to
+    process::READY = true;
 
-    ata.write(0, &[69, 88]);
+    scheduler::idle();
+}
 
-    debug::write(format_args!("[debug] write done!\n"));
+// the kernel command line is a list of key=value options separated by spaces, hz sets the tick
+// frequency, tickless=no keeps idle cpus ticking and quantum is the time slice in milliseconds.
+// has to run before the timer is initialized.
+fn configure(cmdline: &[u8]) {
+    let Ok(cmdline) = core::str::from_utf8(cmdline) else {
+        debug::write(format_args!("[debug] the kernel command line is not utf-8\n"));
 
-    let mut sector: [u8; 512] = [0; 512];
+        return;
+    };
+
+    let mut quantum = None;
+
+    for option in cmdline.split_ascii_whitespace() {
+        let applied = match option.split_once('=') {
+            Some(("hz", value)) => value.parse().map(timer::set_frequency).is_ok(),
+            Some(("tickless", value)) => matches!(value, "yes" | "no").then(|| timer::set_tickless(value == "yes")).is_some(),
+            Some(("quantum", value)) => value.parse::<u64>().map(|ms| quantum = Some(ms)).is_ok(),
+            _ => false,
+        };
+
+        if !applied {
+            debug::write(format_args!("[debug] ignoring kernel option `{}`\n", option));
+        }
+    }
 
-    if let Err(err) = ata.read(0, 1, sector.as_mut_ptr()) {
-        debug::write(format_args!("[debug] failed to read ata drive: {:?}\n", err));
+    // the quantum is kept in ticks, so it depends on the frequency
+    if let Some(ms) = quantum {
+        scheduler::set_quantum(timer::ms_to_ticks(ms));
     }
+}
 
-    debug::write(format_args!("[debug] sector: {:?}\n", sector));
+// finishes the boot in a thread of its own, it can wait for the drives without holding up the
+// scheduler. /bin/init only starts once they are probed.
+fn kinit() {
+    // the drive answers through its irq, which the probe sleeps on
+    match kthread::spawn("ata", ata_test) {
+        Ok(ata) => {
+            let pid = ata.pid();
+
+            match ata.join() {
+                Ok(sector) => debug::write(format_args!("[debug] ata probe {} read back: {:?}\n", pid, &sector[..2])),
+                Err(err) => debug::write(format_args!("[debug] ata probe {} failed: {:?}\n", pid, err)),
+            }
+        },
+        Err(err) => debug::write(format_args!("[debug] failed to spawn the ata thread: {:?}\n", err)),
+    }
 
-    // process::spawn(proc1 as i64);
+    if let Ok(init) = vfs::read("/bin/init") {
+        if let Err(err) = elf::spawn(&init, &["/bin/init"], &[]) {
+            debug::write(format_args!("[debug] failed to spawn /bin/init: {:?}\n", err));
+        }
+    }
+}
 
-    // process::READY = true;
+// writes to the first sector and reads it back
+fn ata_test() -> Result<[u8; 512], AtaError> {
+    let mut ata = ATA.lock();
 
-    loop {}
+    debug::write(format_args!("[debug] ata locked!\n"));
 
-    halt();
+    ata.identify()?;
+
+    debug::write(format_args!("[debug] identify done!\n"));
+
+    ata.write(0, &[69, 88])?;
+
+    debug::write(format_args!("[debug] write done!\n"));
+
+    let mut sector: [u8; 512] = [0; 512];
+
+    ata.read(0, 1, sector.as_mut_ptr())?;
+
+    Ok(sector)
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    apic::ipi::stop_others();
+
     if let Some(tty) = unsafe { KERNEL_TTY.lock().as_mut() } {
         tty.clear();
 
@@ -1,151 +1,515 @@
-use crate::allocator;
+pub mod stack;
+pub mod thread;
 
-use core::alloc::{GlobalAlloc, Layout};
+use crate::fpu::FpuState;
+use crate::interrupt::trap::{self, TrapFrame};
+use crate::vmm::{self, AddressSpace, Region, RegionKind, VmmError};
+use crate::sync::WaitQueue;
+use crate::{debug, scheduler};
+
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use x86_64::instructions::interrupts;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::mem;
+use core::ptr;
+
+use stack::KernelStack;
+use thread::{Thread, Tid};
 
 use spin::Mutex;
 
 pub static mut PROCESS: Mutex<ProcessHandler> = Mutex::new(ProcessHandler::new());
 pub static mut READY: bool = false;
 
-pub const PROCESS_LIMIT: usize = 20;
-pub const STACK_SIZE: usize = 100;
+pub const USER_STACK_TOP: u64 = 0x7fff_ffff_f000;
+pub const USER_STACK_SIZE: u64 = 0x100000;
+
+// the exit code of a process that got killed by the kernel. a process picks its own exit code from
+// 0 to 255, the kernel exits it with the negated number of the signal it stands for.
+pub const KILLED: i64 = -Signal::KILL;
+
+pub type Pid = usize;
+
 
+// the signals a process can be killed with, numbered like on linux
+#[non_exhaustive]
+pub struct Signal;
+
+impl Signal {
+    pub const ILL: i64 = 4;
+    pub const FPE: i64 = 8;
+    pub const KILL: i64 = 9;
+    pub const SEGV: i64 = 11;
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    NoProcess,
+    NoThread,
+    // only processes in ring 3 have an address space of their own
+    NoAddressSpace,
+    Vmm(VmmError),
+}
+
+impl From<VmmError> for ProcessError {
+    fn from(err: VmmError) -> ProcessError {
+        ProcessError::Vmm(err)
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
+    // on the cpu right now
     Running,
-    Waiting,
+    // waiting for its turn on the cpu
+    Ready,
+    // waiting for something like another process to exit
+    Blocked,
+    // waiting for a number of ticks to pass
+    Sleeping,
+    // a process whose threads are all gone, it only holds on to its exit code until the parent
+    // collects it.
+    Zombie,
 }
 
-// TODO: the problem with the crash when the instruction pointer is for some reason set to 0x296 is
-// most likely a result of us not saving r8, r9, r10 and r11
-
-// TODO: never mind it is actually most likely from messed up stack pointer
+// the registers that survive a call to scheduler::switch, everything else is saved in the TrapFrame
+// of the interrupt that led to the switch. `rip` is where switch returns to.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct Context {
-    pub rax: i64,
-    pub rbx: i64,
-    pub rcx: i64,
-    pub rdx: i64,
-    pub rsp: i64,
-    pub rbp: i64,
-    pub rsi: i64,
-    pub rdi: i64,
-    pub rip: i64,
-    pub r8: i64,
-    pub r9: i64,
-    pub r10: i64,
-    pub r11: i64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rip: u64,
 }
 
 impl Context {
     #[inline]
     pub const fn new() -> Context {
         Context {
-            rax: 0,
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
             rbx: 0,
-            rcx: 0,
-            rdx: 0,
-            rsp: 0,
             rbp: 0,
-            rsi: 0,
-            rdi: 0,
             rip: 0,
-            r8: 0,
-            r9: 0,
-            r10: 0,
-            r11: 0,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+pub enum Wait {
+    Exited(Pid, i64),
+    Running,
+    NoChildren,
+}
+
+// everything the threads of a process share, the threads themselves live in ProcessHandler
 pub struct Process {
+    pub pid: Pid,
+    pub name: String,
+    pub parent: Option<Pid>,
+    pub children: Vec<Pid>,
+    pub threads: Vec<Tid>,
+    pub address_space: Option<AddressSpace>,
+    // Running as long as the process has threads, Zombie afterwards
     pub state: State,
-    pub context: Context,
-    pub base: i64,
-    pub stack: i64,
+    pub exit_code: i64,
+    // threads waiting for a child to exit sleep here, shared so a child can wake them up once it
+    // let go of the table.
+    pub exited: Arc<WaitQueue>,
 }
 
 impl Process {
-    pub const fn new() -> Process {
+    pub fn new(pid: Pid, name: &str, parent: Option<Pid>) -> Process {
         Process {
-            state: State::Waiting,
-            context: Context::new(),
-            base: 0,
-            stack: 0,
+            pid,
+            name: name.to_string(),
+            parent,
+            children: Vec::new(),
+            threads: Vec::new(),
+            address_space: None,
+            state: State::Running,
+            exit_code: 0,
+            exited: Arc::new(WaitQueue::new()),
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.base == 0
+    pub fn is_zombie(&self) -> bool {
+        self.state == State::Zombie
+    }
+
+    // the physical address of the page table root that has to be loaded into cr3 before a thread
+    // of the process is allowed to run.
+    pub fn root(&self) -> u64 {
+        match &self.address_space {
+            Some(address_space) => address_space.root().start_address().as_u64(),
+            None => vmm::kernel(|kernel| kernel.root().start_address().as_u64()),
+        }
     }
 }
 
 pub struct ProcessHandler {
-    pub table: [Process; PROCESS_LIMIT],
-    pub pid: usize,
+    pub table: BTreeMap<Pid, Process>,
+    // boxed so a thread stays put while the scheduler holds on to its stack pointer
+    pub threads: BTreeMap<Tid, Box<Thread>>,
+    // pids and tids are drawn from the same counter, which lets the main thread of a process share
+    // its id with the process.
+    pub next_id: usize,
 }
 
 impl ProcessHandler {
     pub const fn new() -> ProcessHandler {
         ProcessHandler {
-            table: [Process::new(); PROCESS_LIMIT],
-            pid: 0,
+            table: BTreeMap::new(),
+            threads: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    // ids are never reused, so a stale id can not accidentally refer to a new process or thread
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+
+        self.next_id - 1
+    }
+
+    // installs a process with a main thread that starts out by returning to `frame`. without an
+    // address space the process only sees the kernel, which is what kernel threads do.
+    pub unsafe fn spawn(&mut self, name: &str, parent: Option<Pid>, address_space: Option<AddressSpace>, frame: TrapFrame) -> Result<Pid, VmmError> {
+        let pid = self.next_id();
+
+        let thread = match Thread::new(pid, frame) {
+            Ok(thread) => thread,
+            Err(err) => {
+                if let Some(address_space) = address_space {
+                    address_space.destroy();
+                }
+
+                return Err(err);
+            },
+        };
+
+        let mut process = Process::new(pid, name, parent);
+
+        process.address_space = address_space;
+        process.threads.push(pid);
+
+        if let Some(parent) = parent.and_then(|parent| self.table.get_mut(&parent)) {
+            parent.children.push(pid);
+        }
+
+        debug::write(format_args!("[debug] spawned process {} ({}), parent: {:?}\n", pid, name, parent));
+
+        self.table.insert(pid, process);
+        self.threads.insert(pid, Box::new(thread));
+
+        scheduler::wake_up(pid, 0);
+
+        Ok(pid)
+    }
+
+    // adds a thread to `pid` that starts out by returning to `frame`
+    pub unsafe fn spawn_thread(&mut self, pid: Pid, frame: TrapFrame) -> Result<Tid, ProcessError> {
+        if !self.table.get(&pid).is_some_and(|proc| !proc.is_zombie()) {
+            return Err(ProcessError::NoProcess);
+        }
+
+        let tid = self.next_id();
+        let thread = Thread::new(pid, frame)?;
+
+        if let Some(proc) = self.table.get_mut(&pid) {
+            proc.threads.push(tid);
+        }
+
+        debug::write(format_args!("[debug] spawned thread {} of process {}\n", tid, pid));
+
+        self.threads.insert(tid, Box::new(thread));
+
+        scheduler::wake_up(tid, 0);
+
+        Ok(tid)
+    }
+
+    // duplicates the process of `tid` into a child that resumes from `frame` as well, with rax set
+    // to 0 so the two can tell each other apart. only the calling thread makes it into the child.
+    pub unsafe fn fork(&mut self, tid: Tid, frame: &TrapFrame) -> Result<Pid, ProcessError> {
+        let thread = self.threads.get_mut(&tid).ok_or(ProcessError::NoThread)?;
+
+        // the registers of the thread are live since it is the one asking for the fork
+        if let Some(fpu) = &mut thread.fpu {
+            fpu.save();
+        }
+
+        let parent = thread.pid;
+        let process = self.table.get_mut(&parent).ok_or(ProcessError::NoProcess)?;
+
+        let address_space = process.address_space.as_mut().ok_or(ProcessError::NoAddressSpace)?.fork()?;
+        let name = process.name.clone();
+
+        let child = self.spawn(&name, Some(parent), Some(address_space), TrapFrame { rax: 0, ..*frame })?;
+
+        let mut fpu = self.threads.get_mut(&child).and_then(|thread| thread.fpu.take());
+
+        if let (Some(fpu), Some(source)) = (&mut fpu, self.threads.get(&tid).and_then(|thread| thread.fpu.as_ref())) {
+            fpu.copy_from(source);
+        }
+
+        if let Some(thread) = self.threads.get_mut(&child) {
+            thread.fpu = fpu;
         }
+
+        Ok(child)
     }
 
-    pub unsafe fn spawn(&mut self, addr: i64) {
-        let stack = allocator::ALLOC.alloc(Layout::new::<[u64; STACK_SIZE]>());
+    // removes `tid`, its process exits with `code` once the last thread is gone. the kernel stack is
+    // left alone when it is still in use, the caller has to take it out beforehand. returns the
+    // queue of the parent if the process exited, see exit.
+    pub unsafe fn exit_thread(&mut self, tid: Tid, code: i64) -> Option<Arc<WaitQueue>> {
+        let mut thread = self.threads.remove(&tid)?;
 
-        self.table[self.pid] = Process::new();
+        thread.release();
 
-        self.table[self.pid].context.rsp = stack as i64;
-        self.table[self.pid].context.rip = addr;
-        self.table[self.pid].base = addr;
-        self.table[self.pid].stack = stack as i64;
+        let last = self.table.get_mut(&thread.pid).is_some_and(|proc| {
+            proc.threads.retain(|other| *other != tid);
 
-        self.table[self.pid].state = State::Waiting;
+            proc.threads.is_empty()
+        });
 
-        // TODO: this creates wierd case where it always jumps to the start.
-        // self.pid += 1;
+        match last {
+            true => self.exit(thread.pid, code),
+            false => None,
+        }
+    }
+
+    // removes every thread of `pid` but `except`, the whole process exits with `code` if there is
+    // no exception. threads on another cpu can not be pulled out from under it, they are marked
+    // and exit on their own once they get to schedule.
+    pub unsafe fn exit_threads(&mut self, pid: Pid, except: Option<Tid>, code: i64) -> Option<Arc<WaitQueue>> {
+        let threads = self.table.get(&pid).map(|proc| proc.threads.clone()).unwrap_or_default();
+
+        let mut exited = None;
+
+        for tid in threads.into_iter().filter(|tid| Some(*tid) != except) {
+            // a thread is on a cpu from the moment it gets picked until switch stores its stack
+            // pointer, it may have marked itself as blocked or sleeping in the meantime.
+            let on_cpu = self.threads.get(&tid).map(|thread| thread.rsp == 0 || thread.state == State::Running);
+
+            match on_cpu {
+                Some(true) => {
+                    if let Some(thread) = self.threads.get_mut(&tid) {
+                        thread.kill = Some(code);
+                    }
+
+                    // it may be on its way into a wait nobody is going to end
+                    self.wake(tid);
+                },
+                _ => exited = exited.or(self.exit_thread(tid, code)),
+            }
+        }
+
+        exited
+    }
+
+    // turns `pid` into a zombie that only holds on to its exit code. returns the queue the parent
+    // waits for its children on, which has to be woken up once the table is unlocked.
+    unsafe fn exit(&mut self, pid: Pid, code: i64) -> Option<Arc<WaitQueue>> {
+        let process = self.table.get_mut(&pid)?;
+
+        if let Some(address_space) = process.address_space.take() {
+            address_space.destroy();
+        }
+
+        process.state = State::Zombie;
+        process.exit_code = code;
+
+        debug::write(format_args!("[debug] process {} ({}) exited with code {}\n", pid, process.name, code));
+
+        let parent = process.parent;
+        let children = mem::take(&mut process.children);
+
+        for child in children {
+            self.orphan(child);
+        }
+
+        match parent.and_then(|parent| self.table.get(&parent)) {
+            Some(parent) => Some(parent.exited.clone()),
+            // nobody is going to collect the exit code
+            None => {
+                self.table.remove(&pid);
+
+                None
+            },
+        }
+    }
+
+    // makes a blocked or sleeping thread ready to run again, returns whether there was one
+    pub fn wake(&mut self, tid: Tid) -> bool {
+        match self.threads.get_mut(&tid).filter(|thread| matches!(thread.state, State::Blocked | State::Sleeping)) {
+            Some(thread) => {
+                thread.state = State::Ready;
+
+                scheduler::wake_up(tid, thread.nice);
+
+                true
+            },
+            None => false,
+        }
+    }
+
+    // collects the exit code of a zombie child of `parent`, any child if `pid` is None
+    pub fn reap(&mut self, parent: Pid, pid: Option<Pid>) -> Wait {
+        let Some(children) = self.table.get(&parent).map(|proc| proc.children.clone()) else {
+            return Wait::NoChildren;
+        };
+
+        let children = children.into_iter()
+            .filter(|child| pid.is_none() || pid == Some(*child))
+            .collect::<Vec<Pid>>();
+
+        let zombie = children.iter()
+            .find(|child| self.table.get(child).is_some_and(|proc| proc.is_zombie()));
+
+        match zombie {
+            Some(child) => {
+                let process = self.table.remove(child).expect("zombie vanished from the table");
+
+                if let Some(parent) = self.table.get_mut(&parent) {
+                    parent.children.retain(|other| other != child);
+                }
+
+                Wait::Exited(*child, process.exit_code)
+            },
+            None if children.is_empty() => Wait::NoChildren,
+            None => Wait::Running,
+        }
+    }
+
+    fn orphan(&mut self, pid: Pid) {
+        if let Some(child) = self.table.get_mut(&pid) {
+            child.parent = None;
+
+            if child.is_zombie() {
+                self.table.remove(&pid);
+            }
+        }
     }
 }
 
-pub fn for_each<F>(f: F) where F: Fn(&mut Process) {
-    unsafe {
-        let mut lock = PROCESS.lock();
+pub fn reserve_user_stack(address_space: &mut AddressSpace) -> Result<(), VmmError> {
+    address_space.reserve(Region::new(
+        VirtAddr::new(USER_STACK_TOP - Size4KiB::SIZE),
+        Size4KiB::SIZE,
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        RegionKind::Stack {
+            limit: VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+        },
+    ))
+}
+
+// the timer interrupt looks at the table too, so it must not fire while we hold the lock
+fn with_handler<F, T>(f: F) -> T where F: FnOnce(&mut ProcessHandler) -> T {
+    interrupts::without_interrupts(|| unsafe { f(&mut PROCESS.lock()) })
+}
 
-        for proc in &mut lock.table {
+pub fn for_each<F>(f: F) where F: Fn(&mut Process) {
+    with_handler(|handler| {
+        for proc in handler.table.values_mut() {
             f(proc);
         }
-    }
+    })
 }
 
-pub fn map<F>(pid: usize, f: F) where F: Fn(&mut Process) {
-    unsafe {
-        let mut lock = PROCESS.lock();
+pub fn map<F, T>(pid: Pid, f: F) -> Option<T> where F: FnOnce(&mut Process) -> T {
+    with_handler(|handler| handler.table.get_mut(&pid).map(f))
+}
 
-        f(&mut lock.table[pid]);
-    }
+pub fn get<F, T>(pid: Pid, f: F) -> Option<T> where F: Fn(&Process) -> T {
+    with_handler(|handler| handler.table.get(&pid).map(f))
+}
+
+pub fn map_thread<F, T>(tid: Tid, f: F) -> Option<T> where F: FnOnce(&mut Thread) -> T {
+    with_handler(|handler| handler.threads.get_mut(&tid).map(|thread| f(thread)))
 }
 
-pub fn get(pid: usize) -> Process {
-    unsafe {
-        let lock = PROCESS.lock();
+// spawns a child of the running process, or a process without a parent if the kernel itself is
+// spawning it.
+pub fn spawn_with(name: &str, address_space: Option<AddressSpace>, frame: TrapFrame) -> Result<Pid, VmmError> {
+    let parent = scheduler::current();
 
-        lock.table[pid]
-    }
+    with_handler(|handler| unsafe { handler.spawn(name, parent, address_space, frame) })
 }
 
-pub fn spawn(addr: i64) {
-    unsafe {
-        let mut lock = PROCESS.lock();
+// takes the thread off the run queue until someone wakes it up, it keeps running until it gives
+// up the cpu though.
+pub fn block(tid: Tid) {
+    map_thread(tid, |thread| thread.state = State::Blocked);
+}
 
-        lock.spawn(addr);
+pub fn wake(tid: Tid) -> bool {
+    with_handler(|handler| handler.wake(tid))
+}
+
+// the new priority applies from the next time the thread gets queued
+pub fn set_nice(tid: Tid, nice: i8) -> bool {
+    map_thread(tid, |thread| thread.nice = nice.clamp(scheduler::queue::NICE_MIN, scheduler::queue::NICE_MAX)).is_some()
+}
+
+pub fn spawn_thread(pid: Pid, frame: TrapFrame) -> Result<Tid, ProcessError> {
+    with_handler(|handler| unsafe { handler.spawn_thread(pid, frame) })
+}
+
+pub fn fork(tid: Tid, frame: &TrapFrame) -> Result<Pid, ProcessError> {
+    with_handler(|handler| unsafe { handler.fork(tid, frame) })
+}
+
+pub fn spawn(name: &str, addr: u64) -> Result<Pid, VmmError> {
+    spawn_with(name, None, TrapFrame::kernel(addr, 0))
+}
+
+// waking the parent takes the table again, so it has to wait until we are done with it
+pub fn exit_thread(tid: Tid, code: i64) {
+    if let Some(exited) = with_handler(|handler| unsafe { handler.exit_thread(tid, code) }) {
+        exited.wake_all();
+    }
+}
+
+pub fn exit_threads(pid: Pid, except: Option<Tid>, code: i64) {
+    if let Some(exited) = with_handler(|handler| unsafe { handler.exit_threads(pid, except, code) }) {
+        exited.wake_all();
     }
 }
 
+// blocks until a child of the running process exits and returns its pid and exit code, or None if
+// there is no such child.
+pub fn wait(pid: Option<Pid>) -> Option<(Pid, i64)> {
+    let tid = scheduler::current_thread()?;
+    let parent = map_thread(tid, |thread| thread.pid)?;
+    let exited = get(parent, |proc| proc.exited.clone())?;
 
+    let mut result = None;
 
+    // the children are checked under the lock of the queue, an exit in between can not be missed
+    exited.wait_until(|| match with_handler(|handler| handler.reap(parent, pid)) {
+        Wait::Exited(pid, code) => {
+            result = Some((pid, code));
+
+            true
+        },
+        Wait::NoChildren => true,
+        Wait::Running => false,
+    });
+
+    result
+}
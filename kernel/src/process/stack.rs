@@ -0,0 +1,111 @@
+use crate::allocator::frame;
+use crate::vmm::{self, VmmError};
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+use spin::Mutex;
+
+use alloc::vec::Vec;
+
+pub const KERNEL_STACK_SIZE: u64 = 0x8000;
+
+// kernel stacks live in their own part of the upper half so they are mapped in every address space,
+// each slot is an unmapped guard page followed by the stack itself.
+const KERNEL_STACKS: u64 = 0xffff_ff00_0000_0000;
+const KERNEL_STACKS_SIZE: u64 = 0x80_0000_0000;
+const GUARD_SIZE: u64 = Size4KiB::SIZE;
+const SLOT_SIZE: u64 = GUARD_SIZE + KERNEL_STACK_SIZE;
+
+static mut SLOTS: Mutex<Slots> = Mutex::new(Slots::new());
+
+
+struct Slots {
+    next: u64,
+    free: Vec<u64>,
+}
+
+impl Slots {
+    const fn new() -> Slots {
+        Slots {
+            next: 0,
+            free: Vec::new(),
+        }
+    }
+
+    // none once every slot of the region is in use
+    fn take(&mut self) -> Option<u64> {
+        if let Some(slot) = self.free.pop() {
+            return Some(slot);
+        }
+
+        if (self.next + 1) * SLOT_SIZE > KERNEL_STACKS_SIZE {
+            return None;
+        }
+
+        self.next += 1;
+
+        Some(self.next - 1)
+    }
+}
+
+pub struct KernelStack {
+    slot: u64,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<KernelStack, VmmError> {
+        let slot = interrupts::without_interrupts(|| unsafe { SLOTS.lock().take() }).ok_or(VmmError::OutOfMemory)?;
+
+        let stack = KernelStack { slot };
+
+        for page in stack.pages() {
+            let result = frame::alloc::<Size4KiB>()
+                .ok_or(VmmError::OutOfMemory)
+                .and_then(|frame| {
+                    vmm::map(page, frame, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).inspect_err(|_| frame::dealloc(frame))
+                });
+
+            if let Err(err) = result {
+                unsafe { stack.free(); }
+
+                return Err(err);
+            }
+        }
+
+        Ok(stack)
+    }
+
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACKS + self.slot * SLOT_SIZE + GUARD_SIZE)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.bottom());
+
+        Page::range(start, start + KERNEL_STACK_SIZE / Size4KiB::SIZE)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+
+    // also cleans up after a partially mapped stack, pages that never got mapped are skipped
+    pub unsafe fn free(self) {
+        for page in self.pages() {
+            if let Ok(frame) = vmm::unmap(page) {
+                frame::dealloc(frame);
+            }
+        }
+
+        interrupts::without_interrupts(|| SLOTS.lock().free.push(self.slot));
+    }
+}
+
+// whether `addr` hits the guard page below one of the kernel stacks, which is what a kernel stack
+// overflow looks like. this runs from the double fault handler so it must not take any locks.
+pub fn is_guard(addr: VirtAddr) -> bool {
+    let offset = addr.as_u64().wrapping_sub(KERNEL_STACKS);
+
+    offset < KERNEL_STACKS_SIZE && offset % SLOT_SIZE < GUARD_SIZE
+}
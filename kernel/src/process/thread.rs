@@ -0,0 +1,72 @@
+use super::*;
+
+pub type Tid = usize;
+
+
+// the part of a process that actually runs, every thread has its own registers and kernel stack
+// but shares the address space with the rest of its process.
+pub struct Thread {
+    pub pid: Pid,
+    pub state: State,
+    // the priority of the thread, see scheduler::queue
+    pub nice: i8,
+    // the kernel stack pointer saved by scheduler::switch, there is always a Context right at it.
+    // it is 0 while the thread runs.
+    pub rsp: u64,
+    pub kernel_stack: Option<KernelStack>,
+    pub fpu: Option<FpuState>,
+    // the exit code of a thread that got killed while running on another cpu
+    pub kill: Option<i64>,
+}
+
+impl Thread {
+    // a thread that starts out by returning to `frame`, a frame without a stack pointer runs on the
+    // kernel stack of the thread.
+    pub unsafe fn new(pid: Pid, mut frame: TrapFrame) -> Result<Thread, VmmError> {
+        let kernel_stack = KernelStack::new()?;
+
+        let Some(fpu) = FpuState::new() else {
+            kernel_stack.free();
+
+            return Err(VmmError::OutOfMemory);
+        };
+
+        if frame.rsp == 0 {
+            // the stack pointer is expected to be misaligned by the return address on entry
+            frame.rsp = kernel_stack.top().as_u64() - 8;
+        }
+
+        // lay the stack out the way switch leaves it behind, so the first switch to the thread
+        // pops an empty Context and returns into trap_return with the frame right above it.
+        let frame_addr = kernel_stack.top().as_u64() - mem::size_of::<TrapFrame>() as u64;
+        let context_addr = frame_addr - mem::size_of::<Context>() as u64;
+
+        ptr::write(frame_addr as *mut TrapFrame, frame);
+        ptr::write(context_addr as *mut Context, Context {
+            rip: trap::trap_return as usize as u64,
+            ..Context::new()
+        });
+
+        Ok(Thread {
+            pid,
+            state: State::Ready,
+            nice: 0,
+            rsp: context_addr,
+            kernel_stack: Some(kernel_stack),
+            fpu: Some(fpu),
+            kill: None,
+        })
+    }
+
+    // the kernel stack is left alone when it is still in use, the caller has to take it out
+    // beforehand.
+    pub unsafe fn release(&mut self) {
+        if let Some(kernel_stack) = self.kernel_stack.take() {
+            kernel_stack.free();
+        }
+
+        if let Some(fpu) = self.fpu.take() {
+            fpu.free();
+        }
+    }
+}
@@ -1,192 +1,418 @@
-use crate::process::{self, *};
-use crate::debug;
+pub mod queue;
 
+use crate::apic::ipi;
+use crate::cpu::{self, Cpu};
+use crate::process::{self, *, stack::KernelStack, thread::Tid};
+use crate::{gdt, timer, vmm};
+
+use x86_64::registers::control::Cr3;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
 use spin::Mutex;
 
+use alloc::vec::Vec;
+
 use core::arch::asm;
+use core::{hint, mem};
+
+use queue::NICE_MAX;
+
+// the number of timer ticks a thread of nice level 0 gets before it has to give up the cpu
+static mut QUANTUM: u64 = DEFAULT_QUANTUM;
 
-pub static mut SCHEDUELER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
-pub static mut NEXT_PROCESS: Process = Process::new();
+const DEFAULT_QUANTUM: u64 = 2;
 
 
+// the scheduling state of a single cpu, every cpu has one in its per cpu data
 pub struct Scheduler {
-    current_pid: usize,
-    initialized: bool,
+    current: Option<Tid>,
+    // the nice level of the current thread and the ticks left of its time slice
+    nice: i8,
+    left: u64,
+    // the stack pointer of the idle task, which is whatever ran on this cpu before its first
+    // thread. it gets the cpu back whenever there is no thread left to run.
+    idle: u64,
+    // the kernel stack of a thread that exited, it can only be freed once we are off it
+    dead: Option<KernelStack>,
+    // whether this cpu gets timer interrupts, without them the idle task can not halt
+    ticking: bool,
 }
 
 impl Scheduler {
     pub const fn new() -> Scheduler {
         Scheduler {
-            current_pid: 0,
-            initialized: false,
+            current: None,
+            nice: 0,
+            left: 0,
+            idle: 0,
+            dead: None,
+            ticking: false,
         }
     }
 
-    pub fn next_pid(&mut self) {
-        if self.current_pid >= PROCESS_LIMIT - 1 {
-            self.current_pid = 0;
-        } else {
-            self.current_pid += 1;
+    // takes the most favoured ready thread off the run queue and starts its time slice. the thread
+    // is marked as running right away, so no other cpu can pick it or free it in the meantime.
+    fn pick(&mut self) -> Option<Tid> {
+        let mut lock = unsafe { PROCESS.lock() };
+
+        let mut skipped = Vec::new();
+
+        let next = loop {
+            let tid = match pop(cpu::current()) {
+                Some(tid) => tid,
+                None => {
+                    // the idle task gives up the cpu as soon as anything is ready
+                    self.left = 0;
+
+                    break None;
+                },
+            };
+
+            // threads that exited since they were queued are skipped
+            let Some(thread) = lock.threads.get_mut(&tid).filter(|thread| thread.state == State::Ready) else {
+                continue;
+            };
+
+            // a thread can be woken up right after it blocked, before the cpu it blocked on got to
+            // schedule. that cpu queues it again if it finds it running, so it has to stay where it
+            // is until it is switched out.
+            if thread.rsp == 0 && self.current != Some(tid) {
+                skipped.push((tid, thread.nice));
+
+                continue;
+            }
+
+            thread.state = State::Running;
+
+            self.nice = thread.nice;
+            self.left = slice(thread.nice);
+
+            break Some(tid);
+        };
+
+        for (tid, nice) in skipped {
+            cpu::current().queue.lock().push(tid, nice);
         }
+
+        next
     }
+}
+
+// our own run queue comes first, an empty one takes work from whichever cpu has some to spare
+fn pop(this: &Cpu) -> Option<Tid> {
+    // the local queue has to be unlocked before stealing, two cpus stealing from each other would
+    // wait on each other's queue forever otherwise.
+    let local = this.queue.lock().pop();
+
+    local.or_else(|| {
+        cpu::all().iter()
+            .filter(|cpu| cpu.index != this.index)
+            .find_map(|cpu| cpu.queue.lock().pop())
+    })
+}
 
-    pub fn next(&mut self, context: Context) {
-        debug::write(format_args!("[debug] context: {:x?}\n", context));
+// the scheduler of the calling cpu, it must not be held with interrupts enabled
+fn this() -> &'static Mutex<Scheduler> {
+    &cpu::current().scheduler
+}
+
+// the time slice of a thread, less favoured threads get fewer ticks but never none
+fn slice(nice: i8) -> u64 {
+    let quantum = unsafe { QUANTUM };
+
+    (quantum * (NICE_MAX as i64 + 1 - nice as i64) as u64 / (NICE_MAX as u64 + 1)).max(1)
+}
+
+pub fn set_quantum(ticks: u64) {
+    unsafe { QUANTUM = ticks.max(1); }
+}
 
-        unsafe {
-            let is_empty = PROCESS.lock().table.iter().all(|process| process.is_empty());
+// queues up a thread that just became ready on the calling cpu
+pub fn enqueue(tid: Tid, nice: i8) {
+    interrupts::without_interrupts(|| cpu::current().queue.lock().push(tid, nice));
+}
 
-            if !is_empty {
-                if !self.initialized {
-                    self.initialized = true;
-                } else {
-                    let mut lock = PROCESS.lock();
+// queues up a thread that just woke up or got created. an idle cpu gets it if there is one, the
+// calling cpu would only get to it once its current thread gives up the cpu.
+pub fn wake_up(tid: Tid, nice: i8) {
+    interrupts::without_interrupts(|| {
+        let this = cpu::current();
 
-                    // TODO: the rip is still not set correctly
+        match cpu::all().iter().find(|cpu| cpu.index != this.index && cpu.is_idle()) {
+            Some(cpu) => {
+                // the next thread that wakes up should look for another cpu
+                cpu.set_idle(false);
 
-                    // we dont want to save context on first context switch
-                    // if lock.table[self.current_pid].context.rip != lock.table[self.current_pid].base {
-                        lock.table[self.current_pid].context = context;
-                        lock.table[self.current_pid].state = State::Waiting;
-                    // }
-                }
+                cpu.queue.lock().push(tid, nice);
 
-                /*
-                process::map(self.current_pid, |proc| {
-                    debug::write(format_args!("[debug] huh: {:x?}\n", context));
+                ipi::reschedule(cpu);
+            },
+            None => this.queue.lock().push(tid, nice),
+        }
+    });
+}
 
-                    proc.context = context;
-                    proc.state = State::Waiting;
+// the tid of the thread running on the calling cpu, if any
+pub fn current_thread() -> Option<Tid> {
+    interrupts::without_interrupts(|| this().lock().current)
+}
 
-                    debug::write(format_args!("[debug] wtf: {:x?}\n", proc.context));
-                });
-                */
+// the pid of the process the running thread belongs to
+pub fn current() -> Option<Pid> {
+    current_thread().and_then(|tid| process::map_thread(tid, |thread| thread.pid))
+}
 
-                /*
-                process::for_each(|context| {
-                    debug::write(format_args!("[debug] context: {:x?}\n", context));
-                });
-                */
+fn reap() {
+    unsafe {
+        if let Some(kernel_stack) = this().lock().dead.take() {
+            kernel_stack.free();
+        }
+    }
+}
 
-                self.next_pid();
+// switches from whatever saves its stack pointer to `from` over to `next`, or back to the idle
+// task if there is nothing to run. returns once something switches back to `from`.
+unsafe fn switch_to(from: *mut u64, next: Option<Tid>) {
+    let (rsp, root) = match next {
+        Some(tid) => {
+            let mut lock = PROCESS.lock();
+            let handler = &mut *lock;
 
-                while process::get(self.current_pid).is_empty() {
-                    self.next_pid();
-                }
+            let thread = handler.threads.get_mut(&tid).expect("switching to a thread that does not exist");
 
-                /*
-                process::map(self.current_pid, |proc| {
-                    proc.state = State::Running;
-                });
-                */
+            // interrupts from ring 3 have to land on the kernel stack of the thread
+            if let Some(kernel_stack) = &thread.kernel_stack {
+                gdt::set_kernel_stack(kernel_stack.top());
+            }
 
-                NEXT_PROCESS = process::get(self.current_pid);
+            // the kernel never touches these registers, so they can be loaded before the switch
+            if let Some(fpu) = &thread.fpu {
+                fpu.restore();
             }
 
-            debug::write(format_args!("[debug] returning from next\n"));
-        }
+            let root = handler.table.get(&thread.pid).expect("thread without a process").root();
+
+            // a running thread has no saved stack pointer, pick only takes threads that have one
+            (mem::take(&mut thread.rsp), root)
+        },
+        None => (this().lock().idle, vmm::kernel(|kernel| kernel.root().start_address().as_u64())),
+    };
+
+    // the kernel half is the same in every address space, so we can keep running after the switch
+    let (frame, flags) = Cr3::read();
+
+    if frame.start_address().as_u64() != root {
+        Cr3::write(PhysFrame::containing_address(PhysAddr::new(root)), flags);
+    }
+
+    switch(from, rsp);
+}
+
+// called from the timer interrupt, the current thread keeps the cpu until its time slice runs out
+// or a more favoured thread is waiting.
+pub fn tick() {
+    let expired = {
+        let mut lock = this().lock();
+
+        lock.ticking = true;
+        lock.left = lock.left.saturating_sub(1);
+
+        lock.left == 0 || cpu::current().queue.lock().best().is_some_and(|nice| nice < lock.nice)
+    };
+
+    if expired {
+        schedule();
+    }
+}
+
+// called from the reschedule ipi after another cpu queued a thread for us. an idle cpu picks it up
+// as soon as the interrupt returns, a busy one only switches if the new thread is more favoured.
+pub fn reschedule() {
+    let preempt = {
+        let lock = this().lock();
+
+        lock.current.is_some() && cpu::current().queue.lock().best().is_some_and(|nice| nice < lock.nice)
+    };
+
+    if preempt {
+        schedule();
     }
 }
 
-#[no_mangle]
-//                         rdi       rsi       rdx       rcx       r8        r9        rsp     rsp + 8  rsp + 16  sp + 24  rsp + 32
-// pub extern "C" fn schedule(rdi: i64, rsi: i64, rdx: i64, rcx: i64, rbp: i64, rsp: i64, rbx: i64, rax: i64, rip: i64, r8: i64, r9: i64, r10: i64, r11: i64) {
-pub extern "C" fn schedule(context: Context) {
+// moves the current thread to the back of its run queue and switches to the most favoured ready
+// thread, which may be the same one. called with interrupts disabled.
+pub fn schedule() {
     unsafe {
-        // debug::write(format_args!("[debug] rsp: {:x?}\n", context.rsp));
-
-        let mut lock = SCHEDUELER.lock();
-
-        /*
-        process::for_each(|context| {
-            debug::write(format_args!("[debug] context: {:x?}\n", context));
-        });
-        */
-
-        lock.next(context);
-
-        /*
-        lock.next(Context {
-            rax,
-            rbx,
-            rcx,
-            rdx,
-            rsp,
-            rbp,
-            rsi,
-            rdi,
-            rip,
-            r8,
-            r9,
-            r10,
-            r11,
-        });
-        */
-
-        /*
-        process::for_each(|context| {
-            debug::write(format_args!("[debug] context: {:x?}\n", context));
-        });
-        */
-
-        debug::write(format_args!("[debug] returning from scheduele: {:x?}\n", NEXT_PROCESS.context));
+        if !process::READY {
+            return;
+        }
+
+        reap();
+
+        let mut lock = this().lock();
+
+        let current = lock.current;
+
+        // a thread that got killed while it was running goes away the next time it gets here
+        if let Some(code) = current.and_then(|tid| process::map_thread(tid, |thread| thread.kill).flatten()) {
+            drop(lock);
+
+            exit_thread(code);
+        }
+
+        // blocked threads are not queued again, whoever wakes them up does that
+        if let Some(tid) = current {
+            let nice = process::map_thread(tid, |thread| {
+                (thread.state == State::Running).then(|| {
+                    thread.state = State::Ready;
+
+                    thread.nice
+                })
+            }).flatten();
+
+            if let Some(nice) = nice {
+                enqueue(tid, nice);
+            }
+        }
+
+        let next = lock.pick();
+
+        if next == current {
+            return;
+        }
+
+        let from = match current.and_then(|tid| process::map_thread(tid, |thread| {
+            if let Some(fpu) = &mut thread.fpu {
+                fpu.save();
+            }
+
+            &mut thread.rsp as *mut u64
+        })) {
+            Some(from) => from,
+            None => &mut lock.idle as *mut u64,
+        };
+
+        lock.current = next;
+
+        // the task we switch to never sees this guard, so it has to go before the switch
+        drop(lock);
+
+        switch_to(from, next);
+    }
+}
+
+// gives up the cpu, the caller keeps going once it gets picked again which may be right away
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+// the idle task of a cpu, which is its boot context once it is done setting things up. it keeps
+// looking for work and sleeps until the next interrupt in between, which only works on a cpu that
+// gets timer interrupts.
+pub fn idle() -> ! {
+    loop {
+        yield_now();
+
+        interrupts::disable();
+
+        let ticking = this().lock().ticking;
+
+        // other cpus hand threads that wake up to us now, and kick us out of the halt with an ipi
+        cpu::current().set_idle(true);
+
+        // a thread that got ready since we looked must not wait for the timer
+        if ticking && cpu::current().queue.lock().best().is_none() {
+            timer::enter_idle();
+
+            interrupts::enable_and_hlt();
+
+            interrupts::without_interrupts(timer::leave_idle);
+        } else {
+            interrupts::enable();
+
+            hint::spin_loop();
+        }
+
+        cpu::current().set_idle(false);
     }
 }
 
-#[no_mangle]
-pub extern "C" fn next_process() {
+// takes the current thread off the cpu for good, `exit` removes it from the table and gets to
+// free the kernel stack if it is not the one we are running on.
+fn leave<F>(exit: F) -> ! where F: FnOnce(Tid, Pid) {
     unsafe {
-        /*
-        debug::write(format_args!("[debug] next process called\n"));
-
-        let current_pid = SCHEDUELER.lock().current_pid;
-
-        let context = process::get(current_pid).context;
-
-        debug::write(format_args!("[debug] context: {:x?}\n", context));
-        */
-
-
-        // NOTE: this simply NOT wrong
-
-        asm!(
-            "mov rax, {rax}",
-            "mov rbx, {rbx}",
-            "mov rcx, {rcx}",
-            "mov rdx, {rdx}",
-            "mov rsi, {rsi}",
-            "mov rdi, {rdi}",
-            "mov rsp, {rsp}",
-            "mov r8, {r8}",
-            "mov r9, {r9}",
-            "mov r10, {r10}",
-            "mov r11, {r11}",
-            "sti",
-            "jmp {rip}",
-            rax = in(reg) NEXT_PROCESS.context.rax,
-            rbx = in(reg) NEXT_PROCESS.context.rbx,
-            rcx = in(reg) NEXT_PROCESS.context.rcx,
-            rdx = in(reg) NEXT_PROCESS.context.rdx,
-            rsi = in(reg) NEXT_PROCESS.context.rsi,
-            rdi = in(reg) NEXT_PROCESS.context.rdi,
-            rsp = in(reg) NEXT_PROCESS.context.rsp,
-            r8 = in(reg) NEXT_PROCESS.context.r8,
-            r9 = in(reg) NEXT_PROCESS.context.r9,
-            r10 = in(reg) NEXT_PROCESS.context.r10,
-            r11 = in(reg) NEXT_PROCESS.context.r11,
-            rip = in(reg) NEXT_PROCESS.context.rip,
-        );
-
-        // x86_64::instructions::interrupts::enable();
-
-        /*
-        asm!(
-            "sti",
-            "jmp {rip}",
-            rip = in(reg) NEXT_PROCESS.context.rip,
-        );
-        */
+        let mut lock = this().lock();
+
+        let tid = lock.current.take().expect("exit called outside of a thread");
+
+        // we are still running on the kernel stack of the thread
+        let (kernel_stack, pid) = process::map_thread(tid, |thread| (thread.kernel_stack.take(), thread.pid))
+            .expect("the current thread is not in the table");
+
+        // the stack of a thread that exited before us may not have been reaped yet, we are long
+        // off that one
+        if let Some(previous) = mem::replace(&mut lock.dead, kernel_stack) {
+            previous.free();
+        }
+
+        // the address space may go away along with the thread
+        vmm::kernel(|kernel| kernel.activate());
+
+        exit(tid, pid);
+
+        let next = lock.pick();
+
+        lock.current = next;
+
+        drop(lock);
+
+        let mut discard = 0;
+
+        switch_to(&mut discard, next);
+
+        unreachable!("switched back to a dead thread");
     }
 }
 
+// ends the current thread, the process goes with it if it was the last one
+pub fn exit_thread(code: i64) -> ! {
+    leave(|tid, _| process::exit_thread(tid, code))
+}
+
+// ends the current process with `code` and moves on to the next thread, threads of the process
+// that run on other cpus follow as soon as they get to schedule.
+pub fn exit(code: i64) -> ! {
+    leave(|tid, pid| {
+        process::exit_threads(pid, Some(tid), code);
+        process::exit_thread(tid, code);
+    })
+}
 
+// saves the callee saved registers of the caller as a Context on its stack, stores the stack
+// pointer in `from` and resumes the Context at `to`. a new task returns into trap_return instead
+// of a call to switch.
+#[naked]
+pub unsafe extern "C" fn switch(from: *mut u64, to: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+
+        "ret",
+        options(noreturn),
+    );
+}
@@ -0,0 +1,45 @@
+use crate::process::thread::Tid;
+
+use alloc::collections::VecDeque;
+
+// nice levels work like on unix, -20 is the most favoured and 19 the least
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+const LEVELS: usize = (NICE_MAX as i16 - NICE_MIN as i16 + 1) as usize;
+
+
+// a fifo of ready threads per nice level, threads of the same level take turns and a level only
+// gets the cpu once every level above it is empty.
+pub struct RunQueue {
+    levels: [VecDeque<Tid>; LEVELS],
+}
+
+impl RunQueue {
+    pub const fn new() -> RunQueue {
+        const EMPTY: VecDeque<Tid> = VecDeque::new();
+
+        RunQueue {
+            levels: [EMPTY; LEVELS],
+        }
+    }
+
+    fn level(nice: i8) -> usize {
+        (nice.clamp(NICE_MIN, NICE_MAX) as i16 - NICE_MIN as i16) as usize
+    }
+
+    pub fn push(&mut self, tid: Tid, nice: i8) {
+        self.levels[Self::level(nice)].push_back(tid);
+    }
+
+    pub fn pop(&mut self) -> Option<Tid> {
+        self.levels.iter_mut().find_map(|level| level.pop_front())
+    }
+
+    // the nice level of the most favoured thread that is waiting, if any
+    pub fn best(&self) -> Option<i8> {
+        self.levels.iter()
+            .position(|level| !level.is_empty())
+            .map(|level| (level as i16 + NICE_MIN as i16) as i8)
+    }
+}
@@ -0,0 +1,38 @@
+use super::mutex::MutexGuard;
+use super::WaitQueue;
+
+
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // releases the mutex and blocks until notified, then takes the mutex again. wakeups may be
+    // spurious, so the caller has to check its condition in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+
+        // we are queued before the mutex is released, a notify in between can not get lost
+        self.waiters.wait_with(|| drop(guard));
+
+        mutex.lock()
+    }
+
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T> where F: FnMut(&mut T) -> bool {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+}
@@ -0,0 +1,130 @@
+pub mod condvar;
+pub mod mutex;
+pub mod semaphore;
+
+use crate::process::{self, thread::Tid};
+use crate::{scheduler, timer};
+
+use x86_64::instructions::interrupts;
+
+use alloc::collections::VecDeque;
+
+
+// threads blocked until someone wakes them up. the queue is only ever touched with interrupts
+// disabled, so interrupt handlers are free to wake threads.
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<Tid>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // checks `condition` and queues the running thread if it does not hold, both under the lock of
+    // the queue so a wakeup can not slip in between. `f` runs once we are queued, then we give up
+    // the cpu until someone wakes us up or `deadline` passes. returns None if the condition held,
+    // and whether there was a thread to block otherwise. has to be called with interrupts disabled.
+    fn block<C, F>(&self, condition: C, f: F, deadline: Option<u64>) -> Option<bool> where C: FnOnce() -> bool, F: FnOnce() {
+        let current = scheduler::current_thread();
+
+        let mut waiters = self.waiters.lock();
+
+        if condition() {
+            return None;
+        }
+
+        let Some(tid) = current else {
+            drop(waiters);
+
+            f();
+
+            return Some(false);
+        };
+
+        process::block(tid);
+
+        waiters.push_back(tid);
+
+        drop(waiters);
+
+        if let Some(deadline) = deadline {
+            timer::wake_at(deadline, tid);
+        }
+
+        f();
+
+        scheduler::schedule();
+
+        // whoever woke us up took us off the queue already, unless it was a spurious wakeup. a
+        // wake_one meant for another waiter must not find us in there.
+        self.waiters.lock().retain(|waiter| *waiter != tid);
+
+        if let Some(deadline) = deadline {
+            timer::cancel(deadline, tid);
+        }
+
+        Some(true)
+    }
+
+    // blocks the running thread and runs `f` once it is queued, so whatever `f` releases can not
+    // wake someone before we are ready to be woken. wakeups may be spurious, and without a thread
+    // to block this just halts until the next interrupt.
+    pub fn wait_with<F>(&self, f: F) where F: FnOnce() {
+        if interrupts::without_interrupts(|| self.block(|| false, f, None)) == Some(false) {
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    // blocks the running thread until `condition` holds
+    pub fn wait_until<F>(&self, mut condition: F) where F: FnMut() -> bool {
+        loop {
+            match interrupts::without_interrupts(|| self.block(&mut condition, || {}, None)) {
+                None => return,
+                Some(true) => {},
+                Some(false) => interrupts::enable_and_hlt(),
+            }
+        }
+    }
+
+    // like wait_until, but gives up once the tick count reaches `deadline`. returns whether the
+    // condition held in time.
+    pub fn wait_until_deadline<F>(&self, mut condition: F, deadline: u64) -> bool where F: FnMut() -> bool {
+        loop {
+            if timer::ticks() >= deadline {
+                return interrupts::without_interrupts(&mut condition);
+            }
+
+            match interrupts::without_interrupts(|| self.block(&mut condition, || {}, Some(deadline))) {
+                None => return true,
+                Some(true) => {},
+                Some(false) => interrupts::enable_and_hlt(),
+            }
+        }
+    }
+
+    // wakes the thread that waited the longest, threads that are gone by now do not count
+    pub fn wake_one(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+
+            while let Some(tid) = waiters.pop_front() {
+                if process::wake(tid) {
+                    return true;
+                }
+            }
+
+            false
+        })
+    }
+
+    pub fn wake_all(&self) {
+        interrupts::without_interrupts(|| {
+            for tid in self.waiters.lock().drain(..) {
+                process::wake(tid);
+            }
+        })
+    }
+}
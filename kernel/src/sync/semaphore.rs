@@ -0,0 +1,53 @@
+use super::WaitQueue;
+use crate::timer;
+
+use x86_64::instructions::interrupts;
+
+
+// a counting semaphore, `signal` never blocks so interrupt handlers can use it to wake up whoever
+// waits on a device.
+pub struct Semaphore {
+    count: spin::Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: spin::Mutex::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_wait(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut count = self.count.lock();
+
+            match *count {
+                0 => false,
+                _ => {
+                    *count -= 1;
+
+                    true
+                },
+            }
+        })
+    }
+
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.try_wait());
+    }
+
+    // gives up after `ticks` timer ticks, returns whether the semaphore was taken
+    pub fn wait_timeout(&self, ticks: u64) -> bool {
+        self.waiters.wait_until_deadline(|| self.try_wait(), timer::ticks() + ticks)
+    }
+
+    pub fn signal(&self) {
+        interrupts::without_interrupts(|| {
+            *self.count.lock() += 1;
+
+            self.waiters.wake_one();
+        });
+    }
+}
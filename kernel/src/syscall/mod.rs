@@ -1,13 +1,80 @@
-use crate::vfs::file;
+use crate::interrupt::trap::TrapFrame;
+use crate::vfs::{self, file};
+use crate::vmm::{AddressSpace, VmmError, USER_END};
+use crate::acpi::fadt;
+use crate::elf::{self, ElfError};
+use crate::process::{self, ProcessError};
+use crate::{debug, keyboard, scheduler, timer};
 
-use core::ffi::CStr;
+use x86_64::VirtAddr;
 
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
+use core::mem::{self, MaybeUninit};
+use core::slice;
+
+const MAX_ARGS: usize = 1024;
+const MAX_READ: usize = 4096;
+const MAX_WRITE: usize = 4096;
+const MAX_STRING: usize = 4096;
+const PAGE_SIZE: usize = 4096;
+
+// the magic numbers reboot wants to be sure it was not called by accident, and its commands
+const REBOOT_MAGIC: [i64; 2] = [0xfee1dead, 672274793];
+const REBOOT_RESTART: i64 = 0x01234567;
+const REBOOT_POWER_OFF: i64 = 0x4321fedc;
+
+
+#[derive(Debug)]
 pub enum SyscallError {
+    InvalidArgument,
     InvalidPath,
+    InvalidPointer,
+    NoChild,
+    NoExec,
+    NoProcess,
+    OutOfMemory,
     Unknown,
 }
 
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> SyscallError {
+        match err {
+            ProcessError::NoProcess | ProcessError::NoThread => SyscallError::NoProcess,
+            ProcessError::NoAddressSpace => SyscallError::InvalidArgument,
+            ProcessError::Vmm(err) => SyscallError::from(err),
+        }
+    }
+}
+
+// anything but running out of frames means the caller handed us an address it can not use
+impl From<VmmError> for SyscallError {
+    fn from(err: VmmError) -> SyscallError {
+        match err {
+            VmmError::OutOfMemory => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidPointer,
+        }
+    }
+}
+
+impl SyscallError {
+    // the negated errno that ends up in rax
+    pub fn code(&self) -> i64 {
+        match self {
+            SyscallError::InvalidPath => -2,
+            SyscallError::NoProcess => -3,
+            SyscallError::NoExec => -8,
+            SyscallError::NoChild => -10,
+            SyscallError::OutOfMemory => -12,
+            SyscallError::InvalidPointer => -14,
+            SyscallError::InvalidArgument => -22,
+            SyscallError::Unknown => -38,
+        }
+    }
+}
+
 #[non_exhaustive]
 pub struct Kind;
 
@@ -15,6 +82,111 @@ impl Kind {
     const READ:  i64 = 0;
     const WRITE: i64 = 1;
     const OPEN:  i64 = 2;
+    const NANOSLEEP: i64 = 35;
+    const THREAD_CREATE: i64 = 56;
+    const FORK:  i64 = 57;
+    const EXECVE: i64 = 59;
+    const EXIT:  i64 = 60;
+    const WAITPID: i64 = 61;
+    const SETPRIORITY: i64 = 141;
+    const REBOOT: i64 = 169;
+    const EXIT_GROUP: i64 = 231;
+}
+
+// the start of `addr..addr + length` if all of it lies in the lower half, user pointers are never
+// trusted to point anywhere near the kernel.
+fn user_range(addr: i64, length: usize) -> Result<VirtAddr, SyscallError> {
+    (addr as u64).checked_add(length as u64)
+        .filter(|end| addr > 0 && *end <= USER_END)
+        .map(|_| VirtAddr::new(addr as u64))
+        .ok_or(SyscallError::InvalidPointer)
+}
+
+// runs `f` on the address space of the running process, a pointer it can not resolve is as bad as
+// one into the kernel.
+fn with_address_space<F>(f: F) -> Result<(), SyscallError> where F: FnOnce(&mut AddressSpace) -> Result<(), VmmError> {
+    let pid = scheduler::current().ok_or(SyscallError::InvalidPointer)?;
+
+    process::map(pid, |proc| {
+        proc.address_space.as_mut()
+            .ok_or(SyscallError::InvalidPointer)
+            .and_then(|address_space| f(address_space).map_err(|_| SyscallError::InvalidPointer))
+    }).unwrap_or(Err(SyscallError::InvalidPointer))
+}
+
+// copies `data` into the address space of the running process
+fn write_user(addr: i64, data: &[u8]) -> Result<(), SyscallError> {
+    let addr = user_range(addr, data.len())?;
+
+    with_address_space(|address_space| address_space.write(addr, data))
+}
+
+// fills `out` from the address space of the running process, anything unmapped is faulted in like
+// it would be for the process itself.
+fn read_bytes(addr: i64, out: &mut [u8]) -> Result<(), SyscallError> {
+    let addr = user_range(addr, out.len())?;
+
+    with_address_space(|address_space| address_space.read(addr, out))
+}
+
+// reads a nul terminated string one page at a time, so a string right below an unmapped page can
+// still be read.
+fn read_string(addr: i64) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut page = [0; PAGE_SIZE];
+
+    loop {
+        let next = addr.checked_add(bytes.len() as i64).ok_or(SyscallError::InvalidPointer)?;
+        let length = PAGE_SIZE - next as usize % PAGE_SIZE;
+
+        read_bytes(next, &mut page[..length])?;
+
+        match page[..length].iter().position(|byte| *byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&page[..end]);
+
+                break;
+            },
+            None => bytes.extend_from_slice(&page[..length]),
+        }
+
+        if bytes.len() > MAX_STRING {
+            return Err(SyscallError::InvalidArgument);
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidPath)
+}
+
+// reads a null terminated array of string pointers like argv
+fn read_strings(addr: i64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    for index in 0..MAX_ARGS as i64 {
+        let entry = addr.checked_add(index * 8).ok_or(SyscallError::InvalidPointer)?;
+
+        match read_user::<i64>(entry)? {
+            0 => return Ok(strings),
+            string => strings.push(read_string(string)?),
+        }
+    }
+
+    Err(SyscallError::InvalidPointer)
+}
+
+// reads a value out of the running process, only for types that are valid for any bit pattern
+fn read_user<T: Copy>(addr: i64) -> Result<T, SyscallError> {
+    let mut value = MaybeUninit::<T>::uninit();
+
+    unsafe {
+        read_bytes(addr, slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>()))?;
+
+        Ok(value.assume_init())
+    }
 }
 
 pub struct Syscall {
@@ -28,24 +200,169 @@ impl Syscall {
         }
     }
 
-    pub fn perform(&self) -> Result<(), SyscallError> {
+    // returns the value that ends up in rax of the caller, `frame` is where the caller resumes
+    pub fn perform(&self, frame: &mut TrapFrame) -> Result<i64, SyscallError> {
         match self.args[0] {
             Kind::READ => {
-                Ok(())
+                // only the keyboard can be read so far, it is what fd 0 stands for
+                if self.args[1] != 0 {
+                    return Ok(0);
+                }
+
+                let mut buffer = vec![0; (self.args[3] as usize).min(MAX_READ)];
+
+                let count = keyboard::read(&mut buffer);
+
+                write_user(self.args[2], &buffer[..count])?;
+
+                Ok(count as i64)
             },
             Kind::WRITE => {
-                Ok(())
+                // stdout and stderr both end up on the serial port until there is a tty for them
+                if !matches!(self.args[1], 1 | 2) {
+                    return Ok(0);
+                }
+
+                let mut buffer = vec![0; (self.args[3] as usize).min(MAX_WRITE)];
+
+                read_bytes(self.args[2], &mut buffer)?;
+
+                debug::write(format_args!("{}", String::from_utf8_lossy(&buffer)));
+
+                Ok(buffer.len() as i64)
             },
             Kind::OPEN => {
-                let mut loader = file::LOADER.lock();
+                let path = read_string(self.args[1])?;
+
+                file::LOADER.lock().open(&path);
+
+                Ok(0)
+            },
+            Kind::NANOSLEEP => {
+                // a timespec is the seconds followed by the nanoseconds
+                let [secs, nanos] = read_user::<[i64; 2]>(self.args[1])?;
+
+                if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+                    return Err(SyscallError::InvalidArgument);
+                }
+
+                timer::sleep(timer::ns_to_ticks((secs as u64).saturating_mul(1_000_000_000).saturating_add(nanos as u64)));
+
+                Ok(0)
+            },
+            Kind::THREAD_CREATE => {
+                let pid = scheduler::current().filter(|_| frame.is_user()).ok_or(SyscallError::Unknown)?;
+
+                // the new thread starts at the entry point on the stack it was given, with the
+                // argument in rdi like a call to it would have.
+                let (entry, stack) = (self.args[1] as u64, self.args[2] as u64);
+
+                if entry == 0 || entry >= USER_END || stack == 0 || stack > USER_END {
+                    return Err(SyscallError::InvalidPointer);
+                }
+
+                let frame = TrapFrame {
+                    rdi: self.args[3] as u64,
+                    ..TrapFrame::user(entry, stack)
+                };
+
+                let tid = process::spawn_thread(pid, frame)?;
+
+                Ok(tid as i64)
+            },
+            Kind::FORK => {
+                let tid = scheduler::current_thread().filter(|_| frame.is_user()).ok_or(SyscallError::Unknown)?;
 
-                unsafe {
-                    let path = CStr::from_ptr(self.args[1] as *const i8);
+                let child = process::fork(tid, frame)?;
 
-                    loader.open(path.to_str().map_err(|_| SyscallError::InvalidPath)?);
+                Ok(child as i64)
+            },
+            Kind::EXECVE => {
+                let tid = scheduler::current_thread().filter(|_| frame.is_user()).ok_or(SyscallError::Unknown)?;
+
+                let path = read_string(self.args[1])?;
+                let argv = read_strings(self.args[2])?;
+                let envp = read_strings(self.args[3])?;
+
+                let data = vfs::read(&path).map_err(|_| SyscallError::InvalidPath)?;
+
+                let argv = argv.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>();
+                let envp = envp.iter().map(|env| env.as_str()).collect::<Vec<&str>>();
+
+                elf::exec(tid, &data, &argv, &envp, frame).map_err(|err| match err {
+                    ElfError::Process(err) => SyscallError::from(err),
+                    ElfError::Vmm(VmmError::OutOfMemory) => SyscallError::OutOfMemory,
+                    _ => SyscallError::NoExec,
+                })?;
+
+                // the new image starts out with rax cleared like every other register
+                Ok(0)
+            },
+            Kind::EXIT => {
+                // only the low byte makes it to the parent, the rest is where kills go
+                scheduler::exit_thread(self.args[1] & 0xff);
+            },
+            Kind::WAITPID => {
+                // a pid of -1 waits for any child
+                let pid = (self.args[1] != -1).then_some(self.args[1] as usize);
+
+                let (pid, code) = process::wait(pid).ok_or(SyscallError::NoChild)?;
+
+                // the status is encoded the way the wait macros of libc expect it, an exit code goes
+                // in the second byte and the signal of a kill in the low bits
+                let status = match code {
+                    code if code < 0 => -code & 0x7f,
+                    code => (code & 0xff) << 8,
+                };
+
+                if self.args[2] != 0 {
+                    write_user(self.args[2], &(status as i32).to_le_bytes())?;
+                }
+
+                Ok(pid as i64)
+            },
+            Kind::SETPRIORITY => {
+                // only PRIO_PROCESS is supported, which names a single thread with 0 meaning the
+                // caller. the nice level of a thread can only be changed from within its process.
+                if self.args[1] != 0 {
+                    return Err(SyscallError::InvalidArgument);
                 }
 
-                Ok(())
+                let pid = scheduler::current().ok_or(SyscallError::Unknown)?;
+
+                let tid = match self.args[2] {
+                    0 => scheduler::current_thread().ok_or(SyscallError::Unknown)?,
+                    tid => tid as usize,
+                };
+
+                if process::map_thread(tid, |thread| thread.pid) != Some(pid) {
+                    return Err(SyscallError::NoProcess);
+                }
+
+                process::set_nice(tid, self.args[3].clamp(i8::MIN as i64, i8::MAX as i64) as i8);
+
+                Ok(0)
+            },
+            Kind::REBOOT => {
+                if self.args[1..3] != REBOOT_MAGIC {
+                    return Err(SyscallError::InvalidArgument);
+                }
+
+                let fadt = fadt::parse().map_err(|_| SyscallError::Unknown)?;
+
+                // either of them only returns if the machine is still running
+                let result = match self.args[3] {
+                    REBOOT_RESTART => fadt.reset(),
+                    REBOOT_POWER_OFF => fadt.shutdown(),
+                    _ => return Err(SyscallError::InvalidArgument),
+                };
+
+                debug::write(format_args!("[debug] reboot {:#x} failed: {:?}\n", self.args[3], result));
+
+                Err(SyscallError::Unknown)
+            },
+            Kind::EXIT_GROUP => {
+                scheduler::exit(self.args[1] & 0xff);
             },
             _ => Err(SyscallError::Unknown),
         }
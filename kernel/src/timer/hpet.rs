@@ -0,0 +1,71 @@
+use crate::acpi::hpet::Hpet;
+use crate::vmm::{self, VmmError};
+
+use x86_64::structures::paging::PageTableFlags;
+
+use core::{hint, ptr};
+
+// the counter ticks at most every 100 ns, the period is given in femtoseconds
+const MAX_PERIOD: u64 = 100_000_000;
+const FEMTOSECONDS_PER_MS: u64 = 1_000_000_000_000;
+
+const ENABLE: u64 = 1 << 0;
+
+#[non_exhaustive]
+struct Register;
+
+impl Register {
+    const CAPABILITIES: usize = 0x00;
+    const CONFIG: usize = 0x10;
+    const COUNTER: usize = 0xf0;
+}
+
+// the main counter of an hpet, which keeps counting up at a fixed rate whatever the cpus do
+pub struct Counter {
+    base: u64,
+    period: u64,
+    mask: u64,
+}
+
+impl Counter {
+    // maps the registers and starts the main counter if the firmware left it stopped, none if the
+    // hpet does not report a sane period.
+    pub fn new(hpet: &Hpet) -> Result<Option<Counter>, VmmError> {
+        let base = vmm::map_physical(hpet.address, 0x400, PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH)?;
+
+        let mut counter = Counter {
+            base: base.as_u64(),
+            period: 0,
+            mask: if hpet.counter_64bit { u64::MAX } else { u32::MAX as u64 },
+        };
+
+        // the high half of the capabilities is the period of the main counter
+        counter.period = counter.read(Register::CAPABILITIES) >> 32;
+
+        if counter.period == 0 || counter.period > MAX_PERIOD {
+            return Ok(None);
+        }
+
+        counter.write(Register::CONFIG, counter.read(Register::CONFIG) | ENABLE);
+
+        Ok(Some(counter))
+    }
+
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base as usize + register) as *const u64) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base as usize + register) as *mut u64, value) }
+    }
+
+    // spins for `ms` milliseconds, a 32 bit counter wraps around every few minutes at worst
+    pub fn spin(&self, ms: u64) {
+        let counts = ms * FEMTOSECONDS_PER_MS / self.period;
+        let start = self.read(Register::COUNTER);
+
+        while self.read(Register::COUNTER).wrapping_sub(start) & self.mask < counts {
+            hint::spin_loop();
+        }
+    }
+}
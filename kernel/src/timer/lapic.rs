@@ -0,0 +1,102 @@
+use crate::apic::{self, Lvt, Register};
+use crate::interrupt::Vector;
+
+use x86_64::registers::model_specific::Msr;
+
+use core::arch::x86_64::{__cpuid, _mm_mfence};
+
+// the timer counts down at the bus clock divided by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const TSC_DEADLINE_MSR: u32 = 0x6e0;
+
+// cpuid 1 ecx, and cpuid 0x80000007 edx for a tsc that keeps its rate through power states
+const HAS_TSC_DEADLINE: u32 = 1 << 24;
+const INVARIANT_TSC: u32 = 1 << 8;
+
+// the number of timer counts in a tick, the same on every cpu. zero until calibrated.
+static mut COUNT: u32 = 0;
+
+// whether the timer is armed with tsc deadlines instead of counts, on every cpu
+static mut DEADLINE_MODE: bool = false;
+
+
+pub fn is_calibrated() -> bool {
+    unsafe { COUNT != 0 }
+}
+
+// lets the timer of the calling cpu count down from its maximum without raising an interrupt
+pub fn start_counting() {
+    apic::write(Register::TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(Register::LVT_TIMER, Lvt::MASKED);
+    apic::write(Register::TIMER_INITIAL, u32::MAX);
+}
+
+// the counts that went by since start_counting, the timer is stopped afterwards
+pub fn stop_counting() -> u32 {
+    let elapsed = u32::MAX - apic::read(Register::TIMER_CURRENT);
+
+    stop();
+
+    elapsed
+}
+
+pub fn set_count(count: u32) {
+    unsafe { COUNT = count; }
+}
+
+// a deadline is only as good as the tsc it is compared against, so an invariant tsc is required too
+pub fn supports_tsc_deadline() -> bool {
+    unsafe {
+        let invariant = __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & INVARIANT_TSC != 0;
+
+        invariant && __cpuid(1).ecx & HAS_TSC_DEADLINE != 0
+    }
+}
+
+pub fn use_tsc_deadline() {
+    unsafe { DEADLINE_MODE = true; }
+}
+
+pub fn is_tsc_deadline() -> bool {
+    unsafe { DEADLINE_MODE }
+}
+
+// interrupts the calling cpu once the tsc reaches `tsc`, a deadline that already passed fires right
+// away. there is no periodic deadline mode, every tick arms the next one.
+pub fn deadline(tsc: u64) {
+    apic::write(Register::LVT_TIMER, Lvt::TSC_DEADLINE | Vector::TIMER as u32);
+
+    unsafe {
+        // the msr write must not overtake the switch to deadline mode
+        _mm_mfence();
+
+        Msr::new(TSC_DEADLINE_MSR).write(tsc);
+    }
+}
+
+// interrupts the calling cpu once every tick
+pub fn periodic() {
+    apic::write(Register::TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(Register::LVT_TIMER, Lvt::PERIODIC | Vector::TIMER as u32);
+    apic::write(Register::TIMER_INITIAL, unsafe { COUNT });
+}
+
+// interrupts the calling cpu once after `ticks` ticks, the timer stays quiet after that until it
+// is programmed again.
+pub fn oneshot(ticks: u64) {
+    let count = (ticks.max(1) * unsafe { COUNT } as u64).min(u32::MAX as u64) as u32;
+
+    apic::write(Register::TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(Register::LVT_TIMER, Vector::TIMER as u32);
+    apic::write(Register::TIMER_INITIAL, count);
+}
+
+pub fn is_periodic() -> bool {
+    apic::read(Register::LVT_TIMER) & Lvt::PERIODIC != 0
+}
+
+pub fn stop() {
+    apic::write(Register::LVT_TIMER, Lvt::MASKED);
+    apic::write(Register::TIMER_INITIAL, 0);
+}
@@ -0,0 +1,324 @@
+pub mod hpet;
+pub mod lapic;
+
+use crate::acpi;
+use crate::apic::{self, ioapic};
+use crate::interrupt::Irq;
+use crate::process::{self, thread::Tid, State};
+use crate::{debug, scheduler};
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use spin::Mutex;
+
+use alloc::collections::BTreeSet;
+
+use core::arch::x86_64::_rdtsc;
+use core::hint;
+
+// the rate every cpu gets timer interrupts at, every tick is 10 ms
+const DEFAULT_FREQUENCY: u64 = 100;
+
+// the divisor of the pit has 16 bits, it can not tick any slower than this
+const MIN_FREQUENCY: u64 = 19;
+const MAX_FREQUENCY: u64 = 1000;
+
+static mut FREQUENCY: u64 = DEFAULT_FREQUENCY;
+
+// whether an idle cpu stops ticking and only wakes up for the next sleeper
+static mut TICKLESS: bool = true;
+
+// a tickless cpu still wakes up this often to look for threads it can steal
+const MAX_IDLE_TICKS: u64 = 10;
+
+// how long the hpet or the pit get to measure the lapic timer and the tsc
+const CALIBRATION_MS: u64 = 50;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+// the number of ticks the pit counted, this is the clock until the tsc is calibrated
+static mut TICKS: Mutex<u64> = Mutex::new(0);
+
+// tsc cycles per tick and the tsc at tick zero, zero as long as the pit is the clock
+static mut TSC_PER_TICK: u64 = 0;
+static mut TSC_START: u64 = 0;
+
+// sleeping threads ordered by the tick they wake up at, the earliest first
+static mut SLEEPERS: Mutex<BTreeSet<(u64, Tid)>> = Mutex::new(BTreeSet::new());
+
+
+#[non_exhaustive]
+pub struct Pit;
+
+impl Pit {
+    const CHANNEL_0: u16 = 0x40;
+    const CHANNEL_2: u16 = 0x42;
+    const COMMAND: u16 = 0x43;
+    // bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 is its output
+    const PORT_B: u16 = 0x61;
+
+    // channel 0, lobyte/hibyte access, square wave generator
+    const SQUARE_WAVE: u8 = 0x36;
+    // channel 2, lobyte/hibyte access, interrupt on terminal count
+    const ONESHOT: u8 = 0xb0;
+}
+
+pub fn frequency() -> u64 {
+    unsafe { FREQUENCY }
+}
+
+// has to be called before init, every deadline is kept in ticks
+pub fn set_frequency(hz: u64) {
+    unsafe { FREQUENCY = hz.clamp(MIN_FREQUENCY, MAX_FREQUENCY); }
+}
+
+pub fn set_tickless(tickless: bool) {
+    unsafe { TICKLESS = tickless; }
+}
+
+// programs channel 0 of the pit to fire at the tick frequency, then moves the boot processor over
+// to its lapic timer if there is a local apic and the timer could be calibrated. the pit stays the
+// clock of last resort otherwise. the lapic timer is armed with tsc deadlines where the cpu has
+// them, they do not drift against the tsc the clock is read from.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / frequency()) as u16;
+
+    unsafe {
+        Port::<u8>::new(Pit::COMMAND).write(Pit::SQUARE_WAVE);
+
+        let mut channel = Port::<u8>::new(Pit::CHANNEL_0);
+
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+
+    if !apic::is_enabled() {
+        return;
+    }
+
+    let (count, cycles) = interrupts::without_interrupts(calibrate);
+
+    if count == 0 || cycles == 0 {
+        debug::write(format_args!("[debug] failed to calibrate the lapic timer, staying on the pit\n"));
+
+        return;
+    }
+
+    interrupts::without_interrupts(|| unsafe {
+        // the tsc picks up counting where the pit left off
+        TSC_START = _rdtsc() - *TICKS.lock() * cycles;
+        TSC_PER_TICK = cycles;
+
+        if let Err(err) = ioapic::mask(Irq::TIMER) {
+            debug::write(format_args!("[debug] failed to mask the pit: {:?}\n", err));
+        }
+
+        lapic::set_count(count);
+
+        if lapic::supports_tsc_deadline() {
+            lapic::use_tsc_deadline();
+        }
+
+        periodic();
+    });
+
+    debug::write(format_args!(
+        "[debug] lapic timer: {} counts per tick, tsc: {} MHz, tsc deadline: {}\n",
+        count, cycles * frequency() / 1_000_000, lapic::is_tsc_deadline(),
+    ));
+}
+
+// starts the lapic timer of an application processor, they only get timer interrupts if the
+// boot processor managed to calibrate its own.
+pub fn init_ap() {
+    if lapic::is_calibrated() {
+        periodic();
+    }
+}
+
+// the tsc value at the start of `tick`
+fn tsc_at(tick: u64) -> u64 {
+    unsafe { TSC_START + tick * TSC_PER_TICK }
+}
+
+// has the calling cpu tick at the start of every tick. a tsc deadline only fires once, so in that
+// mode this is called again from every tick.
+fn periodic() {
+    match lapic::is_tsc_deadline() {
+        true => lapic::deadline(tsc_at(ticks() + 1)),
+        false => lapic::periodic(),
+    }
+}
+
+// lets CALIBRATION_MS pass on the hpet if there is one, on channel 2 of the pit otherwise, and
+// returns how far the lapic timer and the tsc got in a tick meanwhile.
+fn calibrate() -> (u32, u64) {
+    let counter = acpi::hpet::parse().ok()
+        .and_then(|hpet| hpet::Counter::new(&hpet).ok().flatten());
+
+    let (counts, cycles) = match counter {
+        Some(counter) => measure(|| counter.spin(CALIBRATION_MS)),
+        None => calibrate_pit(),
+    };
+
+    let per_tick = |total: u64| total * 1000 / (CALIBRATION_MS * frequency());
+
+    (per_tick(counts).min(u32::MAX as u64) as u32, per_tick(cycles))
+}
+
+// the pit can only count down 55 ms in one go, which is just enough
+fn calibrate_pit() -> (u64, u64) {
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+
+    unsafe {
+        let mut port_b = Port::<u8>::new(Pit::PORT_B);
+
+        // keep the speaker quiet and the channel stopped while it is programmed
+        let gate = port_b.read() & !0b11;
+
+        port_b.write(gate);
+
+        Port::<u8>::new(Pit::COMMAND).write(Pit::ONESHOT);
+
+        let mut channel = Port::<u8>::new(Pit::CHANNEL_2);
+
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let measured = measure(|| {
+            port_b.write(gate | 1);
+
+            while port_b.read() & 0x20 == 0 {
+                hint::spin_loop();
+            }
+        });
+
+        port_b.write(gate);
+
+        measured
+    }
+}
+
+// the lapic timer counts and tsc cycles that went by while `wait` ran
+fn measure<F>(wait: F) -> (u64, u64) where F: FnOnce() {
+    lapic::start_counting();
+
+    let start = unsafe { _rdtsc() };
+
+    wait();
+
+    let cycles = unsafe { _rdtsc() } - start;
+
+    (lapic::stop_counting() as u64, cycles)
+}
+
+pub fn ticks() -> u64 {
+    unsafe {
+        match TSC_PER_TICK {
+            0 => interrupts::without_interrupts(|| *TICKS.lock()),
+            cycles => (_rdtsc() - TSC_START) / cycles,
+        }
+    }
+}
+
+// rounds up so a sleep never ends early
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * frequency()).div_ceil(1000)
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * frequency() as u128).div_ceil(1_000_000_000) as u64
+}
+
+// called from the timer interrupt of every cpu, wakes up every thread whose deadline has passed
+pub fn tick() {
+    unsafe {
+        if TSC_PER_TICK == 0 {
+            *TICKS.lock() += 1;
+        }
+    }
+
+    leave_idle();
+
+    let now = ticks();
+
+    loop {
+        let tid = unsafe {
+            let mut sleepers = SLEEPERS.lock();
+
+            match sleepers.first() {
+                Some((deadline, _)) if *deadline <= now => sleepers.pop_first().map(|(_, tid)| tid),
+                _ => None,
+            }
+        };
+
+        match tid {
+            Some(tid) => {
+                process::wake(tid);
+            },
+            None => break,
+        }
+    }
+}
+
+// blocks the running thread for at least `ticks` ticks. without a thread to block, like during
+// boot, this simply halts until enough ticks went by.
+pub fn sleep(ticks: u64) {
+    let deadline = self::ticks() + ticks;
+
+    while self::ticks() < deadline {
+        let Some(tid) = scheduler::current_thread() else {
+            interrupts::enable_and_hlt();
+
+            continue;
+        };
+
+        // the timer must not see the deadline before the thread is marked as sleeping
+        interrupts::without_interrupts(|| {
+            process::map_thread(tid, |thread| thread.state = State::Sleeping);
+
+            unsafe { SLEEPERS.lock().insert((deadline, tid)); }
+
+            scheduler::schedule();
+        });
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep(ms_to_ticks(ms));
+}
+
+// wakes `tid` up once `deadline` passed, whatever it is blocked on by then. every timeout has to be
+// cancelled once it is no longer needed, or it ends up as a spurious wakeup.
+pub fn wake_at(deadline: u64, tid: Tid) {
+    interrupts::without_interrupts(|| unsafe { SLEEPERS.lock().insert((deadline, tid)); });
+}
+
+pub fn cancel(deadline: u64, tid: Tid) {
+    interrupts::without_interrupts(|| unsafe { SLEEPERS.lock().remove(&(deadline, tid)); });
+}
+
+// called by an idle cpu with interrupts disabled right before it halts. instead of ticking it only
+// wakes up once the first sleeper is due, or after MAX_IDLE_TICKS to look for work.
+pub fn enter_idle() {
+    if !unsafe { TICKLESS } || !lapic::is_calibrated() {
+        return;
+    }
+
+    let next = unsafe { SLEEPERS.lock().first().map(|(deadline, _)| *deadline) };
+
+    let now = ticks();
+    let ticks = next.map_or(MAX_IDLE_TICKS, |deadline| deadline.saturating_sub(now)).min(MAX_IDLE_TICKS);
+
+    match lapic::is_tsc_deadline() {
+        true => lapic::deadline(tsc_at(now + ticks.max(1))),
+        false => lapic::oneshot(ticks),
+    }
+}
+
+// puts the calling cpu back on its periodic tick after enter_idle
+pub fn leave_idle() {
+    if lapic::is_calibrated() && (lapic::is_tsc_deadline() || !lapic::is_periodic()) {
+        periodic();
+    }
+}
@@ -1,13 +1,24 @@
+use crate::sync::{mutex::Mutex, semaphore::Semaphore};
+use crate::timer;
+
 use x86::io;
 
 use core::ptr;
 
 
+// the drive on the primary bus, whoever uses it sleeps on the mutex while another thread waits
+// for its transfer.
+pub static ATA: Mutex<Ata> = Mutex::new(Ata::new());
+
+// signalled by every irq 14, the drive raises one whenever a sector is ready to be read
+static IRQ: Semaphore = Semaphore::new(0);
+
 #[derive(Debug)]
 pub enum AtaError {
     NotFound,
     NotAta,
     Poll,
+    Timeout,
 }
 
 #[non_exhaustive]
@@ -41,7 +52,10 @@ impl Ata {
     const LOW_REGISTER: u16 = 0x1f4;
     const HIGH_REGISTER: u16 = 0x1f5;
 
-    pub fn new() -> Ata {
+    // how long the drive gets to finish a command before we give up on it
+    const TIMEOUT_MS: u64 = 1000;
+
+    pub const fn new() -> Ata {
         Ata {
             sectors: 0,
         }
@@ -57,23 +71,64 @@ impl Ata {
         io::outb(Ata::HIGH_REGISTER, ((lba >> 16) & 0xff) as u8);
     }
 
-    pub fn write(&self, lba: u32, data: &[u8]) {
+    // irqs left over from earlier commands must not be taken for the ones of this command
+    unsafe fn command(&self, command: u8) {
+        while IRQ.try_wait() {}
+
+        io::outb(Ata::SC_REGISTER, command);
+    }
+
+    // reads the status register until `done` holds, the drive gets a millisecond between two reads
+    // and TIMEOUT_MS in total
+    fn poll<F>(&self, done: F) -> Result<u8, AtaError> where F: Fn(u8) -> bool {
+        let deadline = timer::ticks() + timer::ms_to_ticks(Ata::TIMEOUT_MS);
+
+        loop {
+            let status = unsafe { io::inb(Ata::SC_REGISTER) };
+
+            if done(status) {
+                return Ok(status);
+            }
+
+            if timer::ticks() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+
+            timer::sleep_ms(1);
+        }
+    }
+
+    // waits until the drive is done with whatever it was doing and wants data or reports an error
+    fn poll_data(&self) -> Result<(), AtaError> {
+        let status = self.poll(|status| status & Status::BSY == 0 && status & (Status::DRQ | Status::ERR) != 0)?;
+
+        match status & Status::ERR {
+            0 => Ok(()),
+            _ => Err(AtaError::Poll),
+        }
+    }
+
+    pub fn write(&self, lba: u32, data: &[u8]) -> Result<(), AtaError> {
         unsafe {
-            self.setup(lba, (data.len() / 512) as u8 + 1);
+            self.setup(lba, data.len().div_ceil(512) as u8);
 
-            io::outb(Ata::SC_REGISTER, Ata::WRITE);
+            self.command(Ata::WRITE);
 
             for sector in data.chunks(512) {
                 let mut sector = sector.to_vec();
 
                 sector.resize(512, 0);
 
+                self.poll_data()?;
+
                 self.write_sector(&sector);
             }
 
-            io::outb(Ata::SC_REGISTER, Ata::FLUSH);
+            self.command(Ata::FLUSH);
 
-            while io::inb(Ata::SC_REGISTER) & Status::BSY != 0 {}
+            self.poll(|status| status & Status::BSY == 0)?;
+
+            Ok(())
         }
     }
 
@@ -97,10 +152,20 @@ impl Ata {
         unsafe {
             self.setup(lba, sector_count);
 
-            io::outb(Ata::SC_REGISTER, Ata::READ);
+            self.command(Ata::READ);
 
             for _ in 0..sector_count {
-                while io::inb(Ata::SC_REGISTER) & Status::DRQ != 0 {}
+                // sleeps until the drive has the sector instead of spinning on the status register, an
+                // irq that never shows up must not hang us forever though
+                if !IRQ.wait_timeout(timer::ms_to_ticks(Ata::TIMEOUT_MS)) {
+                    return Err(AtaError::Timeout);
+                }
+
+                let status = io::inb(Ata::SC_REGISTER);
+
+                if status & Status::ERR != 0 || status & Status::DRQ == 0 {
+                    return Err(AtaError::Poll);
+                }
 
                 let sector = self.read_sector()?;
 
@@ -152,26 +217,18 @@ impl Ata {
                 io::outb(port, 0);
             }
 
-            io::outb(Ata::SC_REGISTER, Ata::IDENTIFY);
+            self.command(Ata::IDENTIFY);
 
             match io::inb(Ata::SC_REGISTER) {
                 0 => return Err(AtaError::NotFound),
-                _ => while io::inb(Ata::SC_REGISTER) & Status::BSY != 0 {},
-            }
+                _ => self.poll(|status| status & Status::BSY == 0)?,
+            };
 
             if io::inb(Ata::LOW_REGISTER) != 0 || io::inb(Ata::HIGH_REGISTER) != 0 {
                 return Err(AtaError::NotAta);
             }
 
-            let mut status = io::inb(Ata::SC_REGISTER);
-
-            while status & Status::DRQ == 0 && status & Status::ERR == 0 {
-                status = io::inb(Ata::SC_REGISTER);
-            }
-
-            if status & Status::ERR != 0 {
-                return Err(AtaError::Poll);
-            }
+            self.poll_data()?;
 
             let sector = self.read_sector()?;
 
@@ -182,5 +239,11 @@ impl Ata {
     }
 }
 
+// reading the status register makes the drive drop the irq
+pub fn interrupt() {
+    unsafe {
+        io::inb(Ata::SC_REGISTER);
+    }
 
-
+    IRQ.signal();
+}
@@ -1,5 +1,8 @@
 pub mod file;
 pub mod ata;
+pub mod tar;
+
+use crate::debug;
 
 use lazy_static::lazy_static;
 use spin::mutex::Mutex;
@@ -17,6 +20,12 @@ lazy_static! {
 pub enum VfsError {
     DirEntry,
     NotFound,
+    InvalidArchive,
+}
+
+// "." components are dropped so paths coming out of an archive resolve like absolute ones
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|x| !x.is_empty() && *x != ".")
 }
 
 #[derive(Clone)]
@@ -61,13 +70,47 @@ impl Entry {
         Ok(())
     }
 
-    pub fn retrieve(&mut self, path: &str) {
+    pub fn find(&self, path: &str) -> Result<&Entry, VfsError> {
+        let mut entry = self;
+
+        for name in components(path) {
+            match entry {
+                Entry::Directory { entries } => {
+                    entry = entries.get(name).ok_or(VfsError::NotFound)?;
+                },
+                Entry::File { .. } => return Err(VfsError::DirEntry),
+            }
+        }
+
+        Ok(entry)
+    }
+
+    pub fn retrieve(&self, path: &str) -> Result<Vec<u8>, VfsError> {
+        match self.find(path)? {
+            Entry::File { content } => Ok(content.clone()),
+            Entry::Directory { .. } => Err(VfsError::DirEntry),
+        }
+    }
+
+    // creates every missing directory along `path`, like mkdir -p
+    pub fn make_all(&mut self, path: &str) -> Result<(), VfsError> {
+        let mut entry = self;
+
+        for name in components(path) {
+            match entry {
+                Entry::Directory { entries } => {
+                    entry = entries.entry(name.to_string()).or_insert_with(Entry::new_dir);
+                },
+                Entry::File { .. } => return Err(VfsError::DirEntry),
+            }
+        }
+
+        Ok(())
     }
 
     pub fn make<F>(&mut self, path: &str, new: F) -> Result<(), VfsError> where F: Fn() -> Entry {
-        let mut path = path.split('/')
+        let mut path = components(path)
             .map(|x| x.to_string())
-            .filter(|x| !x.is_empty())
             .collect::<Vec<String>>();
 
         let name = path.pop().unwrap_or_default();
@@ -87,9 +130,19 @@ impl Entry {
     }
 }
 
-pub fn init() -> Result<(), VfsError> {
+pub fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    ROOT.lock().retrieve(path)
+}
+
+pub fn init(initrd: Option<&[u8]>) -> Result<(), VfsError> {
     let mut root = ROOT.lock();
 
+    if let Some(archive) = initrd {
+        let count = tar::unpack(&mut root, archive)?;
+
+        debug::write(format_args!("[debug] unpacked {} files from the initrd\n", count));
+    }
+
     root.make("/tty", || Entry::new_dir())?;
 
     root.make("/tty/stdout", || Entry::new_file())?;
@@ -0,0 +1,78 @@
+use super::*;
+
+use alloc::format;
+
+const BLOCK_SIZE: usize = 512;
+
+#[non_exhaustive]
+pub struct Kind;
+
+impl Kind {
+    const FILE: u8 = b'0';
+    const OLD_FILE: u8 = 0;
+    const DIRECTORY: u8 = b'5';
+}
+
+
+fn field(bytes: &[u8]) -> Result<&str, VfsError> {
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+
+    core::str::from_utf8(&bytes[..end]).map_err(|_| VfsError::InvalidArchive)
+}
+
+fn octal(bytes: &[u8]) -> Result<usize, VfsError> {
+    let digits = field(bytes)?.trim_matches(|c: char| c == ' ' || c == '\0');
+
+    if digits.is_empty() {
+        return Ok(0);
+    }
+
+    usize::from_str_radix(digits, 8).map_err(|_| VfsError::InvalidArchive)
+}
+
+// unpacks a ustar archive into `root`, directories are created as needed since archivers do not
+// always store them before their contents. links and extended headers are skipped.
+pub fn unpack(root: &mut Entry, archive: &[u8]) -> Result<usize, VfsError> {
+    let mut offset = 0;
+    let mut count = 0;
+
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+
+        // the archive ends with two zeroed blocks
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+
+        let name = field(&header[0..100])?;
+        let size = octal(&header[124..136])?;
+
+        let path = match &header[257..262] == b"ustar" {
+            true => format!("{}/{}", field(&header[345..500])?, name),
+            false => name.to_string(),
+        };
+
+        let start = offset + BLOCK_SIZE;
+        let end = start.checked_add(size).filter(|end| *end <= archive.len()).ok_or(VfsError::InvalidArchive)?;
+
+        match header[156] {
+            Kind::DIRECTORY => {
+                root.make_all(&path)?;
+            },
+            Kind::FILE | Kind::OLD_FILE => {
+                let parent = path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default();
+
+                root.make_all(parent)?;
+                root.make(&path, || Entry::File { content: archive[start..end].to_vec() })?;
+
+                count += 1;
+            },
+            _ => {},
+        }
+
+        offset = start + size.next_multiple_of(BLOCK_SIZE);
+    }
+
+    Ok(count)
+}
+
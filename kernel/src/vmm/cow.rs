@@ -0,0 +1,141 @@
+use super::*;
+
+use alloc::collections::BTreeMap;
+
+// a software bit marking a page that is writable in its region but mapped read only because the
+// frame may be shared with another address space.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+// the number of address spaces mapping a frame, frames with a single owner are not tracked
+static mut SHARED: Mutex<BTreeMap<PhysAddr, usize>> = Mutex::new(BTreeMap::new());
+
+
+fn share(frame: PhysFrame) {
+    interrupts::without_interrupts(|| unsafe {
+        *SHARED.lock().entry(frame.start_address()).or_insert(1) += 1;
+    });
+}
+
+fn is_shared(frame: PhysFrame) -> bool {
+    interrupts::without_interrupts(|| unsafe { SHARED.lock().contains_key(&frame.start_address()) })
+}
+
+// drops one reference to `frame` and returns whether it was the last one, in which case the
+// caller owns the frame and has to free it.
+pub fn release(frame: PhysFrame) -> bool {
+    interrupts::without_interrupts(|| unsafe {
+        let mut lock = SHARED.lock();
+
+        match lock.get_mut(&frame.start_address()) {
+            Some(count) if *count > 2 => {
+                *count -= 1;
+
+                false
+            },
+            Some(_) => {
+                lock.remove(&frame.start_address());
+
+                false
+            },
+            None => true,
+        }
+    })
+}
+
+unsafe fn walk(addr: PhysAddr, level: usize, base: u64, pages: &mut Vec<(Page, PhysFrame, PageTableFlags)>) {
+    let table = &*allocator::phys_to_virt(addr).as_ptr::<PageTable>();
+
+    for (index, entry) in table.iter().enumerate().filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT)) {
+        let base = base | (index as u64) << (12 + 9 * (level - 1));
+
+        match level {
+            1 => pages.push((Page::containing_address(VirtAddr::new(base)), PhysFrame::containing_address(entry.addr()), entry.flags())),
+            // the lower half is only ever mapped with 4 KiB pages
+            _ if entry.flags().contains(PageTableFlags::HUGE_PAGE) => {},
+            _ => walk(entry.addr(), level - 1, base, pages),
+        }
+    }
+}
+
+impl AddressSpace {
+    // every page mapped in the lower half
+    fn user_pages(&self) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+        let mut pages = Vec::new();
+
+        for (index, entry) in self.table().level_4_table().iter().enumerate().take(256) {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                unsafe { walk(entry.addr(), 3, (index as u64) << 39, &mut pages); }
+            }
+        }
+
+        pages
+    }
+
+    // a copy of the lower half that shares every frame with us, writable pages turn read only in
+    // both address spaces and get copied by whichever side writes to them first.
+    pub fn fork(&mut self) -> Result<AddressSpace, VmmError> {
+        let mut child = AddressSpace::new_process()?;
+
+        child.regions = self.regions.clone();
+
+        for (page, frame, flags) in self.user_pages() {
+            let flags = match flags.contains(PageTableFlags::WRITABLE) {
+                true => (flags - PageTableFlags::WRITABLE) | COW,
+                false => flags,
+            };
+
+            if let Err(err) = child.map(page, frame, flags) {
+                unsafe { child.destroy(); }
+
+                return Err(err);
+            }
+
+            share(frame);
+
+            self.protect(page, flags)?;
+        }
+
+        Ok(child)
+    }
+
+    pub(super) fn is_cow(&self, addr: VirtAddr) -> Option<(Page, PhysFrame, PageTableFlags)> {
+        match self.table().translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } if flags.contains(COW) => {
+                Some((Page::containing_address(addr), frame, flags))
+            },
+            _ => None,
+        }
+    }
+
+    // gives this address space its own writable copy of a cow page, the last owner of a frame
+    // simply takes it over.
+    pub(super) fn copy_on_write(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<PhysFrame, VmmError> {
+        let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+        if !is_shared(frame) {
+            self.protect(page, flags)?;
+
+            return Ok(frame);
+        }
+
+        let copy = frame::alloc::<Size4KiB>().ok_or(VmmError::OutOfMemory)?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                allocator::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                allocator::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                Size4KiB::SIZE as usize,
+            );
+        }
+
+        self.unmap(page).inspect_err(|_| frame::dealloc(copy))?;
+        self.map(page, copy, flags).inspect_err(|_| frame::dealloc(copy))?;
+
+        // someone else may have dropped their reference in the meantime
+        if release(frame) {
+            frame::dealloc(frame);
+        }
+
+        Ok(copy)
+    }
+}
@@ -0,0 +1,534 @@
+pub mod cow;
+
+use crate::allocator::{self, frame::{self, FrameSource}};
+use crate::apic::ipi;
+use crate::debug;
+
+use limine::memory_map::EntryType;
+use limine::response::{KernelAddressResponse, MemoryMapResponse};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, MappedFrame, MapperFlush, TranslateResult, UnmapError};
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+use spin::Mutex;
+
+use alloc::vec::Vec;
+
+use core::ptr;
+
+pub static mut KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+// the end of the lower half, everything below it belongs to the process that is running
+pub const USER_END: u64 = 0x8000_0000_0000;
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+
+#[derive(Debug)]
+pub enum VmmError {
+    OutOfMemory,
+    AlreadyMapped,
+    NotMapped,
+    HugePage,
+    Overlap,
+    NoRegion,
+    Protection,
+}
+
+impl<S: PageSize> From<MapToError<S>> for VmmError {
+    fn from(err: MapToError<S>) -> VmmError {
+        match err {
+            MapToError::FrameAllocationFailed => VmmError::OutOfMemory,
+            MapToError::ParentEntryHugePage => VmmError::HugePage,
+            MapToError::PageAlreadyMapped(_) => VmmError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for VmmError {
+    fn from(err: UnmapError) -> VmmError {
+        match err {
+            UnmapError::ParentEntryHugePage => VmmError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => VmmError::NotMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for VmmError {
+    fn from(err: FlagUpdateError) -> VmmError {
+        match err {
+            FlagUpdateError::ParentEntryHugePage => VmmError::HugePage,
+            FlagUpdateError::PageNotMapped => VmmError::NotMapped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Anonymous,
+    // grows downwards one page at a time whenever something below `start` is touched, as long as
+    // the address stays above `limit`.
+    Stack {
+        limit: VirtAddr,
+    },
+}
+
+// a range of virtual memory that is backed lazily, frames are only allocated once a page is touched.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn new(start: VirtAddr, size: u64, flags: PageTableFlags, kind: RegionKind) -> Region {
+        Region {
+            start: start.align_down(Size4KiB::SIZE),
+            end: (start + size).align_up(Size4KiB::SIZE),
+            flags,
+            kind,
+        }
+    }
+
+    // the lowest address this region can ever cover
+    pub fn bottom(&self) -> VirtAddr {
+        match self.kind {
+            RegionKind::Anonymous => self.start,
+            RegionKind::Stack { limit } => limit,
+        }
+    }
+
+    pub fn covers(&self, addr: VirtAddr) -> bool {
+        addr >= self.bottom() && addr < self.end
+    }
+
+    fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        !(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !self.flags.contains(PageTableFlags::WRITABLE)
+            || error_code.contains(PageFaultErrorCode::USER_MODE) && !self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && self.flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+pub struct AddressSpace {
+    root: PhysFrame,
+    regions: Vec<Region>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, VmmError> {
+        Ok(AddressSpace {
+            root: zeroed_frame()?,
+            regions: Vec::new(),
+        })
+    }
+
+    // a fresh address space for a process, the lower half starts out empty and the upper half is
+    // shared with the kernel.
+    pub fn new_process() -> Result<AddressSpace, VmmError> {
+        let space = AddressSpace::new()?;
+
+        kernel(|kernel| {
+            let source = kernel.table();
+            let mut table = space.table();
+
+            for index in 256..512 {
+                table.level_4_table_mut()[index] = source.level_4_table()[index].clone();
+            }
+        });
+
+        Ok(space)
+    }
+
+    // releases every frame mapped in the lower half together with the page tables themselves, the
+    // upper half belongs to the kernel and is left alone.
+    pub unsafe fn destroy(self) {
+        if self.is_active() {
+            kernel(|kernel| kernel.activate());
+        }
+
+        let table = self.table();
+
+        for entry in table.level_4_table().iter().take(256) {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                free_table(entry.addr(), 3);
+            }
+        }
+
+        frame::dealloc(self.root);
+    }
+
+    pub fn root(&self) -> PhysFrame {
+        self.root
+    }
+
+    fn table(&self) -> OffsetPageTable<'static> {
+        unsafe {
+            let table = &mut *allocator::phys_to_virt(self.root.start_address()).as_mut_ptr::<PageTable>();
+
+            OffsetPageTable::new(table, VirtAddr::new(allocator::HHDM))
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.root
+    }
+
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.root, Cr3Flags::empty());
+    }
+
+    // the upper half is shared between every address space, so changes there always need to be
+    // flushed while changes to an inactive lower half will be flushed by the next cr3 switch.
+    fn flush<S: PageSize>(&self, page: Page<S>, flush: MapperFlush<S>) {
+        if self.is_active() || u16::from(page.p4_index()) >= 256 {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+
+    pub fn map<S: PageSize>(&mut self, page: Page<S>, frame: PhysFrame<S>, flags: PageTableFlags) -> Result<(), VmmError> where for<'a> OffsetPageTable<'a>: Mapper<S> {
+        let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+        let flush = unsafe {
+            self.table().map_to_with_table_flags(page, frame, flags | PageTableFlags::PRESENT, parent, &mut FrameSource)?
+        };
+
+        self.flush(page, flush);
+
+        Ok(())
+    }
+
+    // other cpus may still have the old translation cached, for the upper half or for this address
+    // space if a thread of its process runs there. a new mapping can not be cached anywhere yet, but
+    // a removed or restricted one has to be shot down before anyone reuses the frame.
+    fn shootdown<S: PageSize>(&self, page: Page<S>) {
+        let root = (u16::from(page.p4_index()) < 256).then(|| self.root.start_address().as_u64());
+
+        ipi::flush_tlb(root, page.start_address());
+    }
+
+    pub fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, VmmError> where for<'a> OffsetPageTable<'a>: Mapper<S> {
+        let (frame, flush) = self.table().unmap(page)?;
+
+        self.flush(page, flush);
+        self.shootdown(page);
+
+        Ok(frame)
+    }
+
+    pub fn protect<S: PageSize>(&mut self, page: Page<S>, flags: PageTableFlags) -> Result<(), VmmError> where for<'a> OffsetPageTable<'a>: Mapper<S> {
+        let flush = unsafe {
+            self.table().update_flags(page, flags | PageTableFlags::PRESENT)?
+        };
+
+        self.flush(page, flush);
+        self.shootdown(page);
+
+        Ok(())
+    }
+
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.table().translate_addr(addr)
+    }
+
+    pub fn reserve(&mut self, region: Region) -> Result<(), VmmError> {
+        if self.regions.iter().any(|other| region.bottom() < other.end && other.bottom() < region.end) {
+            return Err(VmmError::Overlap);
+        }
+
+        self.regions.push(region);
+
+        Ok(())
+    }
+
+    // backs the page containing `addr` with a zeroed frame if it belongs to one of our regions,
+    // anything else is a genuine fault that the caller has to deal with.
+    pub fn handle_fault(&mut self, addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), VmmError> {
+        let region = self.regions.iter()
+            .find(|region| region.covers(addr))
+            .ok_or(VmmError::NoRegion)?;
+
+        if !region.permits(error_code) {
+            return Err(VmmError::Protection);
+        }
+
+        // the only protection violation we resolve is a write to a page shared after a fork
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return match (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE), self.is_cow(addr)) {
+                (true, Some((page, frame, flags))) => self.copy_on_write(page, frame, flags).map(|_| ()),
+                _ => Err(VmmError::Protection),
+            };
+        }
+
+        self.populate(addr).map(|_| ())
+    }
+
+    // makes sure the page containing `addr` is backed by memory and returns its frame
+    pub fn populate(&mut self, addr: VirtAddr) -> Result<PhysFrame, VmmError> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+
+        if let Some(frame) = self.translate(page.start_address()) {
+            return Ok(PhysFrame::containing_address(frame));
+        }
+
+        let region = self.regions.iter_mut()
+            .find(|region| region.covers(addr))
+            .ok_or(VmmError::NoRegion)?;
+
+        if addr < region.start {
+            debug::write(format_args!("[debug] growing stack from {:x?} to {:x?}\n", region.start, page.start_address()));
+
+            region.start = page.start_address();
+        }
+
+        let flags = region.flags;
+        let frame = zeroed_frame()?;
+
+        self.map(page, frame, flags).inspect_err(|_| frame::dealloc(frame))?;
+
+        Ok(frame)
+    }
+
+    // the counterpart to write, pages that are not backed yet are faulted in like they would be for
+    // the process itself.
+    pub fn read(&mut self, addr: VirtAddr, out: &mut [u8]) -> Result<(), VmmError> {
+        let mut offset = 0;
+
+        while offset < out.len() {
+            let source = addr + offset as u64;
+
+            let frame = self.populate(source)?;
+
+            let within = source.as_u64() % Size4KiB::SIZE;
+            let length = (Size4KiB::SIZE - within).min((out.len() - offset) as u64) as usize;
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    allocator::phys_to_virt(frame.start_address() + within).as_ptr::<u8>(),
+                    out[offset..].as_mut_ptr(),
+                    length,
+                );
+            }
+
+            offset += length;
+        }
+
+        Ok(())
+    }
+
+    // copies `data` into this address space through the hhdm, so it works whether or not the
+    // address space is active and regardless of the page permissions.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), VmmError> {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let target = addr + offset as u64;
+
+            // writing through the hhdm would otherwise change the page for everyone sharing it
+            let frame = match self.is_cow(target) {
+                Some((page, frame, flags)) => self.copy_on_write(page, frame, flags)?,
+                None => self.populate(target)?,
+            };
+
+            let within = target.as_u64() % Size4KiB::SIZE;
+            let length = (Size4KiB::SIZE - within).min((data.len() - offset) as u64) as usize;
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data[offset..].as_ptr(),
+                    allocator::phys_to_virt(frame.start_address() + within).as_mut_ptr::<u8>(),
+                    length,
+                );
+            }
+
+            offset += length;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn zeroed_frame() -> Result<PhysFrame, VmmError> {
+    let frame = frame::alloc::<Size4KiB>().ok_or(VmmError::OutOfMemory)?;
+
+    unsafe {
+        ptr::write_bytes(allocator::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+    }
+
+    Ok(frame)
+}
+
+unsafe fn free_table(addr: PhysAddr, level: usize) {
+    let table = &*allocator::phys_to_virt(addr).as_ptr::<PageTable>();
+
+    for entry in table.iter().filter(|entry| entry.flags().contains(PageTableFlags::PRESENT)) {
+        match level {
+            1 => {
+                let frame = PhysFrame::containing_address(entry.addr());
+
+                if cow::release(frame) {
+                    frame::dealloc::<Size4KiB>(frame);
+                }
+            },
+            2 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => frame::dealloc::<Size2MiB>(PhysFrame::containing_address(entry.addr())),
+            3 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => frame::dealloc::<Size1GiB>(PhysFrame::containing_address(entry.addr())),
+            _ => free_table(entry.addr(), level - 1),
+        }
+    }
+
+    frame::dealloc::<Size4KiB>(PhysFrame::containing_address(addr));
+}
+
+// maps the physical range [start, end) at `offset`, using 2 MiB pages wherever the range allows it.
+fn map_range(space: &mut AddressSpace, start: u64, end: u64, offset: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    let mut addr = start & !(Size4KiB::SIZE - 1);
+
+    while addr < end {
+        let result = if addr % Size2MiB::SIZE == 0 && offset % Size2MiB::SIZE == 0 && addr + Size2MiB::SIZE <= end {
+            space.map::<Size2MiB>(
+                Page::containing_address(VirtAddr::new(addr + offset)),
+                PhysFrame::containing_address(PhysAddr::new(addr)),
+                flags,
+            ).map(|_| Size2MiB::SIZE)
+        } else {
+            space.map::<Size4KiB>(
+                Page::containing_address(VirtAddr::new(addr + offset)),
+                PhysFrame::containing_address(PhysAddr::new(addr)),
+                flags,
+            ).map(|_| Size4KiB::SIZE)
+        };
+
+        match result {
+            Ok(size) => addr += size,
+            // memory map entries are not guaranteed to be page aligned so neighbours may share a page
+            Err(VmmError::AlreadyMapped) => addr += Size4KiB::SIZE,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn map_section(space: &mut AddressSpace, kernel: &KernelAddressResponse, start: &u8, end: &u8, flags: PageTableFlags) -> Result<(), VmmError> {
+    let start = start as *const u8 as u64;
+    let end = end as *const u8 as u64;
+
+    let offset = kernel.virtual_base() - kernel.physical_base();
+
+    map_range(space, start - offset, end - offset, offset, flags)
+}
+
+pub fn init(memory_map: &MemoryMapResponse, kernel: &KernelAddressResponse) {
+    let mut space = AddressSpace::new().expect("failed to allocate the kernel page table");
+
+    // every upper half entry is allocated up front, that way the kernel half can be copied into new
+    // address spaces and stay in sync with the kernel page table forever.
+    {
+        let mut table = space.table();
+
+        for entry in table.level_4_table_mut().iter_mut().skip(256) {
+            let frame = zeroed_frame().expect("failed to allocate the kernel page table");
+
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    let hhdm = unsafe { allocator::HHDM };
+
+    for entry in memory_map.entries() {
+        let flags = match entry.entry_type {
+            EntryType::USABLE
+                | EntryType::BOOTLOADER_RECLAIMABLE
+                | EntryType::KERNEL_AND_MODULES
+                | EntryType::ACPI_RECLAIMABLE
+                | EntryType::ACPI_NVS => PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            EntryType::FRAMEBUFFER => PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | PageTableFlags::WRITE_THROUGH,
+            _ => continue,
+        };
+
+        map_range(&mut space, entry.base, entry.base + entry.length, hhdm, flags).expect("failed to map the hhdm");
+    }
+
+    unsafe {
+        map_section(&mut space, kernel, &__text_start, &__text_end, PageTableFlags::empty())
+            .expect("failed to map .text");
+
+        map_section(&mut space, kernel, &__rodata_start, &__rodata_end, PageTableFlags::NO_EXECUTE)
+            .expect("failed to map .rodata");
+
+        map_section(&mut space, kernel, &__data_start, &__data_end, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .expect("failed to map .data");
+
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
+        space.activate();
+
+        debug::write(format_args!("[debug] switched to kernel page table: {:x?}\n", space.root()));
+
+        *KERNEL_SPACE.lock() = Some(space);
+    }
+}
+
+// application processors come up on the page tables of limine, they switch to ours with the same
+// paging features the boot processor turned on.
+pub fn init_ap() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    kernel(|space| unsafe { space.activate() });
+}
+
+pub fn kernel<F, T>(f: F) -> T where F: FnOnce(&mut AddressSpace) -> T {
+    interrupts::without_interrupts(|| {
+        let mut lock = unsafe { KERNEL_SPACE.lock() };
+
+        f(lock.as_mut().expect("kernel address space is not initialized"))
+    })
+}
+
+pub fn map<S: PageSize>(page: Page<S>, frame: PhysFrame<S>, flags: PageTableFlags) -> Result<(), VmmError> where for<'a> OffsetPageTable<'a>: Mapper<S> {
+    kernel(|space| space.map(page, frame, flags))
+}
+
+pub fn unmap<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, VmmError> where for<'a> OffsetPageTable<'a>: Mapper<S> {
+    kernel(|space| space.unmap(page))
+}
+
+// makes the physical range [start, start + size) reachable through the hhdm, which only covers the
+// memory limine told us about. pages that are already mapped keep their flags, so this is meant
+// for things like firmware tables and device registers.
+pub fn map_physical(start: PhysAddr, size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmmError> {
+    let hhdm = unsafe { allocator::HHDM };
+
+    kernel(|space| {
+        let mut addr = start.align_down(Size4KiB::SIZE);
+
+        while addr < start + size {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr.as_u64() + hhdm));
+
+            if space.translate(page.start_address()).is_none() {
+                space.map(page, PhysFrame::containing_address(addr), flags | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+            }
+
+            addr += Size4KiB::SIZE;
+        }
+
+        Ok(allocator::phys_to_virt(start))
+    })
+}
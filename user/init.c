@@ -0,0 +1,193 @@
+// the first program the kernel runs. there is no libc yet, so it talks to the kernel through
+// int 0x80 itself. it checks that processes and threads behave and then echoes the keyboard.
+
+#define SYS_READ 0
+#define SYS_WRITE 1
+#define SYS_NANOSLEEP 35
+#define SYS_THREAD_CREATE 56
+#define SYS_FORK 57
+#define SYS_EXECVE 59
+#define SYS_EXIT 60
+#define SYS_WAITPID 61
+#define SYS_EXIT_GROUP 231
+
+#define CHILD_CODE 7
+#define SIGSEGV 11
+#define STACK_SIZE 16384
+
+typedef unsigned long u64;
+
+static long syscall(long number, long a, long b, long c) {
+    long result;
+
+    __asm__ volatile ("int $0x80" : "=a"(result) : "a"(number), "D"(a), "S"(b), "d"(c) : "memory");
+
+    return result;
+}
+
+static u64 length(const char *string) {
+    u64 length = 0;
+
+    while (string[length]) {
+        length++;
+    }
+
+    return length;
+}
+
+static void print(const char *string) {
+    syscall(SYS_WRITE, 1, (long)string, length(string));
+}
+
+static int equal(const char *a, const char *b) {
+    while (*a && *a == *b) {
+        a++;
+        b++;
+    }
+
+    return *a == *b;
+}
+
+static void sleep_ms(long ms) {
+    long timespec[2] = { ms / 1000, ms % 1000 * 1000000 };
+
+    syscall(SYS_NANOSLEEP, (long)timespec, 0, 0);
+}
+
+static __attribute__((noreturn)) void fail(const char *message) {
+    print("init: ");
+    print(message);
+    print("\n");
+
+    syscall(SYS_EXIT_GROUP, 1, 0, 0);
+
+    __builtin_unreachable();
+}
+
+// a forked child replaces itself with a fresh copy of init that only exits
+static void check_fork_exec(void) {
+    long pid = syscall(SYS_FORK, 0, 0, 0);
+
+    if (pid < 0) {
+        fail("fork failed");
+    }
+
+    if (pid == 0) {
+        const char *argv[] = { "/bin/init", "child", 0 };
+        const char *envp[] = { 0 };
+
+        syscall(SYS_EXECVE, (long)"/bin/init", (long)argv, (long)envp);
+
+        fail("exec failed");
+    }
+
+    int status = 0;
+
+    if (syscall(SYS_WAITPID, pid, (long)&status, 0) != pid) {
+        fail("waitpid did not return the child");
+    }
+
+    if (status != CHILD_CODE << 8) {
+        fail("the child exited with the wrong status");
+    }
+
+    print("init: fork, exec and waitpid work\n");
+}
+
+// a child that touches memory it does not have is killed, which the status tells apart from an exit
+static void check_fault(void) {
+    long pid = syscall(SYS_FORK, 0, 0, 0);
+
+    if (pid < 0) {
+        fail("fork failed");
+    }
+
+    if (pid == 0) {
+        *(volatile int *)0 = 0;
+
+        fail("writing to address 0 did not fault");
+    }
+
+    int status = 0;
+
+    if (syscall(SYS_WAITPID, pid, (long)&status, 0) != pid) {
+        fail("waitpid did not return the child");
+    }
+
+    if (status != SIGSEGV) {
+        fail("the child was not killed by SIGSEGV");
+    }
+
+    print("init: faulting children are killed\n");
+}
+
+static volatile long thread_arg;
+static char thread_stack[STACK_SIZE] __attribute__((aligned(16)));
+
+static __attribute__((noreturn)) void thread_main(long arg) {
+    thread_arg = arg;
+
+    syscall(SYS_EXIT, 0, 0, 0);
+
+    __builtin_unreachable();
+}
+
+// the thread shares our memory, so we see what it wrote once it ran
+static void check_thread(void) {
+    // the entry point is jumped to, the stack has to look like a call just pushed its return address
+    long stack = (long)(thread_stack + STACK_SIZE - 8);
+
+    if (syscall(SYS_THREAD_CREATE, (long)thread_main, stack, 42) < 0) {
+        fail("thread_create failed");
+    }
+
+    for (int tries = 0; thread_arg != 42; tries++) {
+        if (tries == 100) {
+            fail("the thread never ran");
+        }
+
+        sleep_ms(10);
+    }
+
+    print("init: threads work\n");
+}
+
+static __attribute__((noreturn)) void echo(void) {
+    char buffer[64];
+
+    for (;;) {
+        long count = syscall(SYS_READ, 0, (long)buffer, sizeof(buffer));
+
+        if (count > 0) {
+            syscall(SYS_WRITE, 1, (long)buffer, count);
+        }
+    }
+}
+
+__attribute__((noreturn, used)) void start(u64 *stack) {
+    long argc = stack[0];
+    const char **argv = (const char **)&stack[1];
+
+    if (argc > 1 && equal(argv[1], "child")) {
+        syscall(SYS_EXIT_GROUP, CHILD_CODE, 0, 0);
+    }
+
+    print("init: running in ring 3\n");
+
+    check_fork_exec();
+    check_fault();
+    check_thread();
+
+    echo();
+}
+
+// rsp points at argc on entry and is 16 byte aligned, start gets it as its argument and a properly
+// aligned stack.
+__asm__ (
+    ".globl _start\n"
+    "_start:\n"
+    "    xor %rbp, %rbp\n"
+    "    mov %rsp, %rdi\n"
+    "    call start\n"
+    "    ud2\n"
+);